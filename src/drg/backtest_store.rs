@@ -0,0 +1,82 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::model::{Context, StrategyParams};
+use crate::utils::db::ClientMongo;
+use mongodb::bson::{self, doc, Document};
+use serde::Serialize;
+use std::error::Error;
+
+const COL_RUNS: &str = "runs";
+const COL_TRADES: &str = "trades";
+const COL_EQUITIES: &str = "equities";
+const COL_PORTFOLIO: &str = "portfolio";
+
+// 把回测结果保存到 mongo, 按 (run_id, stg_name) 区分每次运行:
+// runs: 每次运行一条, 包含参数和绩效统计
+// trades/equities/portfolio: 每条交易记录/权益快照一条, 带 run_id 和 stg_name
+#[derive(Debug, Clone)]
+pub struct BacktestStore {
+    client: ClientMongo,
+}
+
+impl BacktestStore {
+    pub fn new(client: ClientMongo) -> Self {
+        BacktestStore { client }
+    }
+    pub fn with_db_name(db_name: String) -> Self {
+        Self::new(ClientMongo::with_db_name(db_name))
+    }
+    pub fn db_name(&self) -> &str {
+        self.client.db_name()
+    }
+
+    // 同一个 run_id 重复保存时覆盖之前的结果
+    pub async fn save(&self, run_id: &str, timestamp: i64, params: &StrategyParams, context: &Context) -> Result<(), Box<dyn Error>> {
+        let key = doc! {"run_id": run_id, "stg_name": &params.stg_name};
+        let run = doc! {
+            "run_id": run_id,
+            "stg_name": &params.stg_name,
+            "timestamp": timestamp,
+            "params": bson::to_bson(params)?,
+            "profits": bson::to_bson(&context.profits)?,
+        };
+        self.client.upsert(COL_RUNS, key.clone(), run).await?;
+
+        let mut items: Vec<&String> = context.trade_records.keys().collect();
+        items.sort();
+        let trades = items.iter().flat_map(|item| &context.trade_records[*item]);
+        self.replace_rows(COL_TRADES, &key, trades).await?;
+
+        let mut items: Vec<&String> = context.equities.keys().collect();
+        items.sort();
+        let equities = items.iter().flat_map(|item| &context.equities[*item]);
+        self.replace_rows(COL_EQUITIES, &key, equities).await?;
+        self.replace_rows(COL_PORTFOLIO, &key, &context.portfolio).await?;
+        Ok(())
+    }
+
+    // 删除 key 对应的旧记录, 再插入 rows, 每条记录加上 key 的字段
+    async fn replace_rows<T: Serialize>(&self, collection_name: &str, key: &Document, rows: impl IntoIterator<Item = T>) -> Result<usize, Box<dyn Error>> {
+        let mut documents = Vec::new();
+        for row in rows {
+            let mut document = key.clone();
+            document.extend(bson::to_document(&row)?);
+            documents.push(document);
+        }
+        self.client.delete_many(collection_name, key.clone()).await?;
+        if documents.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.client.insert_many(collection_name, documents).await?)
+    }
+}
+
+impl Default for BacktestStore {
+    fn default() -> Self {
+        Self::with_db_name("backtests".to_string())
+    }
+}
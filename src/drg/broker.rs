@@ -4,74 +4,43 @@
 // Author: quantxyz
 // Email: lktsepc@gmail.com
 
-use super::model::{Candle, CandleHelper, Event};
-use crate::utils::db::ClientMongo;
-use mongodb::bson::{self, doc};
+use super::model::{Candle, Event};
+use super::source::{CandleStream, DataSource};
+use crate::utils::common;
+use futures::future::join_all;
+use futures::stream::StreamExt;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
+// K 线 channel 的容量, 策略处理不过来时 broker 暂停读取数据源
+pub const DATA_BUFFER: usize = 4096;
+
 #[derive(Debug, Clone)]
 pub struct BrokerLocal {
+    // 策略内部产生的订单/持仓等事件
     pub event_sender: mpsc::UnboundedSender<Event>,
-}
-
-pub async fn get_candles(
-    symbol: &str,
-    interval: &str,
-    timestamp_start: i64,
-    timestamp_end: i64,
-) -> Vec<Candle> {
-    let label = format!("{}_{}", symbol, interval);
-    let mut filter = doc! {"_id": {"$gt": timestamp_start}};
-    if timestamp_end > 0 && timestamp_end > timestamp_start {
-        let end_condition = doc! {"_id": {"$lt": timestamp_end}};
-        let and_filter = doc! {"$and": vec![filter, end_condition]};
-        filter = and_filter;
-    }
-    let client = ClientMongo::with_db_name("cryptodb".to_string());
-    let result = client.records_query(&label, Some(filter), None, None, None).await;
-    match result {
-        Ok(records) => {
-            let candles: Vec<Candle> = records
-                .into_iter()
-                .filter_map(|doc| {
-                    // 尝试从Document中反序列化CandleHelper
-                    let candle_helper: CandleHelper = match bson::from_document(doc) {
-                        Ok(helper) => helper,
-                        Err(e) => {
-                            log::error!("{:?}", e);
-                            return None;
-                        } // 如果反序列化失败，跳过这个文档
-                    };
-
-                    // 手动转换CandleHelper为Candle
-                    Some(Candle {
-                        symbol: symbol.to_string(),
-                        timestamp: candle_helper.timestamp,
-                        open: candle_helper.open,
-                        high: candle_helper.high,
-                        low: candle_helper.low,
-                        close: candle_helper.close,
-                        volume: candle_helper.volume,
-                        interval: candle_helper.interval,
-                    })
-                })
-                .collect();
-            candles
-        }
-        Err(e) => {
-            log::error!("get_candles error: {:?}", e);
-            Vec::new()
-        }
-    }
+    // 行情数据, 有界, 只有运行中的 broker 任务持有, 任务结束(包括 panic)时 channel 关闭
+    pub data_sender: Option<mpsc::Sender<Event>>,
+    pub data_source: Arc<dyn DataSource>,
 }
 
 impl BrokerLocal {
-    pub fn new(event_sender: mpsc::UnboundedSender<Event>) -> Self {
+    pub fn with_data_source(
+        event_sender: mpsc::UnboundedSender<Event>,
+        data_sender: mpsc::Sender<Event>,
+        data_source: Arc<dyn DataSource>,
+    ) -> Self {
         BrokerLocal {
             event_sender,
+            data_sender: Some(data_sender),
+            data_source,
         }
     }
 
+    // 按时间顺序合并所有 item 的 K 线: 收盘时间 -> 开盘时间(晚的优先) -> 周期 -> 币种,
+    // 大周期 K 线在其包含的小周期 K 线都走完后才发送, 避免提前看到整根 K 线
     pub async fn start(
         &self,
         symbols: &Vec<String>,
@@ -79,62 +48,167 @@ impl BrokerLocal {
         items_timestamp_start: &std::collections::HashMap<String, i64>,
         items_timestamp_end: &std::collections::HashMap<String, i64>,
     ) {
-        let mut tasks = vec![];
-        for s in symbols {
-            let symbol = s.clone();
-            let intervals = intervals.clone();
-            let items_timestamp_start = items_timestamp_start.clone();
-            let items_timestamp_end = items_timestamp_end.clone();
-            let event_sender = self.event_sender.clone();
-
-            let task = tokio::spawn(async move {
-                for interval in intervals {
-                    if [
-                        "1m", "3m", "5m", "15m", "30m", "1h", "2h", "4h", "6h", "8h", "12h", "1d",
-                        "3d", "1w", "1M",
-                    ]
-                    .contains(&interval.as_str())
-                    {
-                        let item = format!("{}_{}", symbol, interval);
-                        let timestamp_start = items_timestamp_start.get(&item).unwrap_or(&0).to_owned();
-                        let timestamp_end = items_timestamp_end.get(&item).unwrap_or(&0).to_owned();
-                        let _candles = get_candles(&symbol, &interval, timestamp_start, timestamp_end).await;
-                        let candles_ref = if _candles.len() >= 1 {
-                            &_candles[.._candles.len() - 1]
-                        } else {
-                            &[]
-                        };
-                        let candles: Vec<Candle> = candles_ref.to_vec();
-                        for candle in candles {
-                            let _t = if candle.timestamp.to_string().len() == 10 {
-                                candle.timestamp * 1000
-                            } else {
-                                candle.timestamp
-                            };
-                            let c = Candle {
-                                symbol: candle.symbol,
-                                timestamp: _t,
-                                open: candle.open,
-                                high: candle.high,
-                                low: candle.low,
-                                close: candle.close,
-                                volume: candle.volume,
-                                interval: candle.interval,
-                            };
-                            // Call on_candle_event
-                            if let Err(e) = event_sender.send(Event::EventCandle(c)) {
-                                eprintln!("Failed to send candle event: {}", e);
-                            }
-                        }
-                    }
+        let data_sender = match &self.data_sender {
+            Some(data_sender) => data_sender,
+            None => {
+                log::error!("broker has no data_sender, already started");
+                return;
+            }
+        };
+        let mut feeds = vec![];
+        for symbol in symbols {
+            for interval in intervals {
+                if ![
+                    "1m", "3m", "5m", "15m", "30m", "1h", "2h", "4h", "6h", "8h", "12h", "1d",
+                    "3d", "1w", "1M",
+                ]
+                .contains(&interval.as_str())
+                {
+                    continue;
                 }
-            });
-            tasks.push(task);
+                let item = format!("{}_{}", symbol, interval);
+                let timestamp_start = items_timestamp_start.get(&item).unwrap_or(&0).to_owned();
+                let timestamp_end = items_timestamp_end.get(&item).unwrap_or(&0).to_owned();
+                let candles = self.data_source.stream_candles(symbol, interval, timestamp_start, timestamp_end);
+                feeds.push(ItemFeed::new(item, candles));
+            }
+        }
+
+        let heads = join_all(feeds.iter_mut().map(|feed| feed.start())).await;
+        let mut heap = BinaryHeap::new();
+        for (index, head) in heads.into_iter().enumerate() {
+            if let Some(candle) = head {
+                heap.push(Reverse(FeedHead::new(candle, index)));
+            }
+        }
+        while let Some(Reverse(head)) = heap.pop() {
+            let index = head.index;
+            // Call on_candle_event, channel 满时等待策略消费
+            if let Err(e) = data_sender.send(Event::EventCandle(head.candle)).await {
+                eprintln!("Failed to send candle event: {}", e);
+                return;
+            }
+            if let Some(candle) = feeds[index].advance().await {
+                heap.push(Reverse(FeedHead::new(candle, index)));
+            }
+        }
+        // 所有数据源都已读完, 通知策略结束
+        if let Err(e) = data_sender.send(Event::EventFinish()).await {
+            eprintln!("Failed to send finish event: {}", e);
+        }
+    }
+}
+
+// 一个 item 的 K 线流, 预读一根, 最后一根 K 线可能还没走完, 不发送
+struct ItemFeed {
+    item: String,
+    candles: CandleStream,
+    next: Option<Candle>,
+}
+
+impl ItemFeed {
+    fn new(item: String, candles: CandleStream) -> Self {
+        ItemFeed {
+            item,
+            candles,
+            next: None,
+        }
+    }
+
+    async fn read(&mut self) -> Option<Candle> {
+        while let Some(result) = self.candles.next().await {
+            match result {
+                Ok(candle) => {
+                    let _t = common::normalize_timestamp_millis(candle.timestamp);
+                    return Some(Candle {
+                        timestamp: _t,
+                        ..candle
+                    });
+                }
+                Err(e) => log::error!("{} candles error: {:?}", self.item, e),
+            }
+        }
+        None
+    }
+
+    async fn start(&mut self) -> Option<Candle> {
+        self.next = self.read().await;
+        self.advance().await
+    }
+
+    // 返回下一根后面还有数据的 K 线
+    async fn advance(&mut self) -> Option<Candle> {
+        let candle = self.next.take()?;
+        self.next = self.read().await;
+        self.next.as_ref().map(|_| candle)
+    }
+}
+
+struct FeedHead {
+    key: (i64, Reverse<i64>, String, String),
+    candle: Candle,
+    index: usize,
+}
+
+impl FeedHead {
+    fn new(candle: Candle, index: usize) -> Self {
+        let close_time = common::interval_close_time(candle.timestamp, &candle.interval);
+        let key = (close_time, Reverse(candle.timestamp), candle.interval.clone(), candle.symbol.clone());
+        FeedHead { key, candle, index }
+    }
+}
+
+impl PartialEq for FeedHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.index == other.index
+    }
+}
+
+impl Eq for FeedHead {}
+
+impl PartialOrd for FeedHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeedHead {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.key, self.index).cmp(&(&other.key, other.index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candle(symbol: &str, interval: &str, timestamp: i64) -> Candle {
+        Candle {
+            symbol: symbol.to_string(),
+            timestamp,
+            open: 1.0,
+            high: 1.0,
+            low: 1.0,
+            close: 1.0,
+            volume: 1.0,
+            interval: interval.to_string(),
         }
+    }
 
-        for task in tasks {
-            let _ = task.await;
+    #[test]
+    fn higher_interval_after_contained_bars() {
+        let hour = 60*60*1000;
+        let mut heap = BinaryHeap::new();
+        heap.push(Reverse(FeedHead::new(candle("BTCUSDT", "1d", 0), 0)));
+        for i in 0..24 {
+            heap.push(Reverse(FeedHead::new(candle("BTCUSDT", "1h", i*hour), 1)));
         }
-        
+        let order: Vec<(String, i64)> = std::iter::from_fn(|| heap.pop())
+            .map(|Reverse(head)| (head.candle.interval, head.candle.timestamp))
+            .collect();
+        assert_eq!(order.len(), 25);
+        assert_eq!(order[0], ("1h".to_string(), 0));
+        assert_eq!(order[23], ("1h".to_string(), 23*hour));
+        assert_eq!(order[24], ("1d".to_string(), 0));
     }
 }
@@ -0,0 +1,103 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// VIP 等级按最近 30 天的成交额计算
+const VOLUME_WINDOW_MILLIS: i64 = 30*24*60*60*1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FeeRate {
+    pub maker: f64,
+    pub taker: f64,
+}
+
+// 30 天成交额达到 min_volume 后使用的费率
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FeeTier {
+    pub min_volume: f64,
+    pub maker: f64,
+    pub taker: f64,
+}
+
+// 手续费: 限价单成交按 maker, 其他按 taker
+// {"default": {"maker": 0.001, "taker": 0.001},
+//  "tiers": [{"min_volume": 1000000, "maker": 0.0009, "taker": 0.001}],
+//  "discount": 0.25,
+//  "symbols": {"BTCUSDT": {"maker": 0.0, "taker": 0.0}}}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeSchedule {
+    pub default: FeeRate,
+    // VIP 等级, 取满足条件的最高一级
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    // BNB 抵扣等折扣比例, 如 0.25 表示手续费打 75 折
+    #[serde(default)]
+    pub discount: f64,
+    // 指定币种的费率, 优先于 VIP 等级
+    #[serde(default)]
+    pub symbols: HashMap<String, FeeRate>,
+}
+
+impl FeeSchedule {
+    // maker/taker 相同的单一费率
+    pub fn flat(rate: f64) -> Self {
+        FeeSchedule {
+            default: FeeRate { maker: rate, taker: rate },
+            tiers: Vec::new(),
+            discount: 0.0,
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn rate(&self, symbol: &str, is_maker: bool, volume_30d: f64) -> f64 {
+        let rate = self.symbols.get(symbol).copied().unwrap_or_else(|| {
+            self.tiers
+                .iter()
+                .filter(|tier| volume_30d >= tier.min_volume)
+                .max_by(|a, b| a.min_volume.total_cmp(&b.min_volume))
+                .map(|tier| FeeRate { maker: tier.maker, taker: tier.taker })
+                .unwrap_or(self.default)
+        });
+        let rate = if is_maker { rate.maker } else { rate.taker };
+        rate*(1.0 - self.discount)
+    }
+}
+
+// 滚动 30 天成交额
+#[derive(Debug, Default)]
+pub struct VolumeWindow {
+    fills: VecDeque<(i64, f64)>,
+    total: f64,
+}
+
+impl VolumeWindow {
+    pub fn push(&mut self, timestamp: i64, notional: f64) {
+        self.fills.push_back((timestamp, notional));
+        self.total += notional;
+    }
+
+    // timestamp 之前 30 天内的成交额
+    pub fn total(&mut self, timestamp: i64) -> f64 {
+        while let Some((t, notional)) = self.fills.front().copied() {
+            if t > timestamp - VOLUME_WINDOW_MILLIS {
+                break;
+            }
+            self.total -= notional;
+            self.fills.pop_front();
+        }
+        self.total.max(0.0)
+    }
+}
@@ -8,4 +8,11 @@
 pub mod model;
 pub mod broker;
 pub mod strategy;
+pub mod source;
+pub mod slippage;
+pub mod fee;
+pub mod stats;
+pub mod results;
+pub mod report;
+pub mod backtest_store;
 
@@ -4,8 +4,11 @@
 // Author: quantxyz
 // Email: lktsepc@gmail.com
 
+use super::fee::{FeeSchedule, VolumeWindow};
+use super::slippage::SlippageConfig;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::str::FromStr;
 
 // 自定义反序列化函数，用于将字符串转换为f64
 fn deserialize_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
@@ -52,26 +55,138 @@ pub struct Position {
     pub highest: f64,
     pub lowest: f64,
     pub stop_loss: f64,
+    // 跟踪止损价, 0 表示没有
+    pub trailing_stop: f64,
     pub take_profit: f64,
     pub timestamp: i64,
 }
 
+// 订单类型
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OrderType {
+    // 按 price 立即成交
+    Market,
+    // 价格到达 price 或更优时成交
+    Limit,
+    // 价格突破 stop_price 后按市价成交
+    Stop,
+    // 价格突破 stop_price 后变为 price 的限价单
+    StopLimit,
+}
+
+// 订单状态, 每次变化都推送 on_order
+// New -> PartiallyFilled -> Filled, 挂单可能 Cancelled/Expired, 参数错误或资金不足为 Rejected
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+    Expired,
+}
+
+impl OrderStatus {
+    // 还在订单簿中等待成交
+    pub fn is_open(&self) -> bool {
+        matches!(self, OrderStatus::New | OrderStatus::PartiallyFilled)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Order {
+    // 下单时分配, 从 1 开始递增
+    pub id: u64,
     pub item: String,
+    // 市价单为成交价, 限价单为限价
     pub price: f64,
+    // 正数买, 负数卖
     pub qty: f64,
     pub timestamp: i64,
+    pub order_type: OrderType,
+    pub status: OrderStatus,
+    // 止损单/止损限价单的触发价
+    pub stop_price: f64,
+    // K 线开盘时间到达 expire_at 时过期, 0 表示一直有效
+    pub expire_at: i64,
+    // 成交均价, 未成交为 0
+    pub fill_price: f64,
+    // 已成交数量, 与 qty 同号
+    pub filled_qty: f64,
+    // 成交的滑点成本
+    pub slippage: f64,
+    // 成交的手续费
+    pub fee: f64,
+}
+
+impl Order {
+    pub fn market(item: &str, qty: f64, price: f64, timestamp: i64) -> Self {
+        Order {
+            id: 0,
+            item: item.to_string(),
+            price,
+            qty,
+            timestamp,
+            order_type: OrderType::Market,
+            status: OrderStatus::New,
+            stop_price: 0.0,
+            expire_at: 0,
+            fill_price: 0.0,
+            filled_qty: 0.0,
+            slippage: 0.0,
+            fee: 0.0,
+        }
+    }
+    pub fn limit(item: &str, qty: f64, price: f64, timestamp: i64) -> Self {
+        Order {
+            order_type: OrderType::Limit,
+            ..Order::market(item, qty, price, timestamp)
+        }
+    }
+    pub fn stop(item: &str, qty: f64, stop_price: f64, timestamp: i64) -> Self {
+        Order {
+            order_type: OrderType::Stop,
+            stop_price,
+            ..Order::market(item, qty, 0.0, timestamp)
+        }
+    }
+    pub fn stop_limit(item: &str, qty: f64, stop_price: f64, price: f64, timestamp: i64) -> Self {
+        Order {
+            order_type: OrderType::StopLimit,
+            stop_price,
+            ..Order::market(item, qty, price, timestamp)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Equity {
     pub item: String,
     pub timestamp: i64,
+    // cash_aval + 持仓保证金 + unrealized_pnl
     pub equity_value: f64,
     pub close_latest: f64,
     pub pos_size: f64,
     pub cash_aval: f64,
+    // 按 close_latest 计算的持仓浮动盈亏
+    pub unrealized_pnl: f64,
+    // 累计已实现盈亏, 已扣手续费
+    pub realized_pnl: f64,
+    // 持仓市值 |pos_size| * close_latest
+    pub exposure: f64,
+}
+
+// 组合账户, 所有 item 共用资金时的汇总
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PortfolioEquity {
+    pub timestamp: i64,
+    pub equity_value: f64,
+    pub cash_aval: f64,
+    // 所有持仓占用的保证金
+    pub margin_used: f64,
+    pub unrealized_pnl: f64,
+    pub realized_pnl: f64,
+    pub exposure: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -84,6 +199,12 @@ pub struct TradeRecord {
     pub price_close: f64,
     pub time_close: i64,
     pub label_close: String,
+    // 开仓和平仓的滑点成本合计
+    pub slippage: f64,
+    // 开仓和平仓的手续费合计
+    pub fee: f64,
+    // 平仓的已实现盈亏, 未扣手续费, 未平仓为 0
+    pub pnl: f64,
 }
 
 pub enum Event {
@@ -101,8 +222,22 @@ pub struct Context {
     pub positions: HashMap<String, Position>,
     pub trade_records: HashMap<String, Vec<TradeRecord>>,
     pub equities: HashMap<String, Vec<Equity>>,
+    // 组合账户的权益曲线, 只在 is_portfolio 时记录
+    pub portfolio: Vec<PortfolioEquity>,
     pub atrs: HashMap<String, f64>,
     pub profits: Vec<Profit>,
+    // 未成交的挂单, 按下单顺序撮合
+    pub orders: HashMap<String, Vec<Order>>,
+    // 订单的每次状态变化(新建/成交/撤单/过期等)
+    pub order_history: Vec<Order>,
+    // 上一个订单 id
+    pub last_order_id: u64,
+    // 滚动 30 天成交额, 用于计算 VIP 等级
+    pub volume_30d: VolumeWindow,
+    // 每个 item 累计的手续费
+    pub fees: HashMap<String, f64>,
+    // 分批止盈进度: item -> (已止盈的档数, 开仓时的仓位大小)
+    pub tp_ladders: HashMap<String, (usize, f64)>,
 }
 
 impl Context {
@@ -112,8 +247,15 @@ impl Context {
             positions: HashMap::new(),
             trade_records: HashMap::new(),
             equities: HashMap::new(),
+            portfolio: Vec::new(),
             atrs: HashMap::new(),
             profits: Vec::new(),
+            orders: HashMap::new(),
+            order_history: Vec::new(),
+            last_order_id: 0,
+            volume_30d: VolumeWindow::default(),
+            fees: HashMap::new(),
+            tp_ladders: HashMap::new(),
         }
     }
     pub fn push_candle(&mut self, candle: Candle) {
@@ -164,21 +306,184 @@ impl Context {
     pub fn get_atr(&self, item: &str) -> Option<f64> {
         self.atrs.get(item).cloned()
     }
+    pub fn push_order(&mut self, order: Order) {
+        self.orders
+            .entry(order.item.to_string())
+            .or_default()
+            .push(order);
+    }
+    pub fn get_orders(&self, item: &str) -> &[Order] {
+        self.orders.get(item).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+    // 所有未成交的挂单, 按 id 排序
+    pub fn open_orders(&self) -> Vec<&Order> {
+        let mut orders: Vec<&Order> = self.orders.values().flatten().collect();
+        orders.sort_by_key(|o| o.id);
+        orders
+    }
+    pub fn get_order(&self, id: u64) -> Option<&Order> {
+        self.orders.values().flatten().find(|o| o.id == id)
+    }
+    // 从订单簿中移除并返回挂单
+    pub fn remove_order(&mut self, id: u64) -> Option<Order> {
+        for orders in self.orders.values_mut() {
+            if let Some(index) = orders.iter().position(|o| o.id == id) {
+                return Some(orders.remove(index));
+            }
+        }
+        None
+    }
+    pub fn next_order_id(&mut self) -> u64 {
+        self.last_order_id += 1;
+        self.last_order_id
+    }
     pub fn push_profit(&mut self, profit: Profit) {
         self.profits.push(profit);
     }
+    pub fn get_profit(&self, item: &str) -> Option<&Profit> {
+        self.profits.iter().find(|p| p.item == item)
+    }
 }
-#[derive(Debug)]
+// 回测结束后的绩效统计, item 为 "portfolio" 时是所有 item 合并的结果
+// 收益率/回撤都是小数, 0.1 表示 10%
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Profit {
-    item: String,
-    is_use_percent_of_equity: bool,
-    percent_of_every_trade_money: f64,
-    percent_of_equity: f64,
-    initial_capital: f64,
-    every_trade_fee: f64,
+    pub item: String,
+    pub initial_capital: f64,
+    pub final_equity: f64,
+    pub total_return: f64,
+    // 年化收益率
+    pub cagr: f64,
+    // 年化波动率
+    pub volatility: f64,
+    // 无风险利率按 0 计算
+    pub sharpe: f64,
+    pub sortino: f64,
+    // cagr / max_drawdown
+    pub calmar: f64,
+    pub max_drawdown: f64,
+    // 最长的回撤持续时间(毫秒), 从前高到重新创新高
+    pub max_drawdown_duration: i64,
+    // 已平仓交易数
+    pub trades: usize,
+    pub win_rate: f64,
+    // 总盈利 / 总亏损
+    pub profit_factor: f64,
+    // 平均每笔净盈亏(扣手续费)
+    pub expectancy: f64,
+    pub avg_win: f64,
+    // 平均亏损, 负数
+    pub avg_loss: f64,
+    // 有持仓的时间占比
+    pub exposure_time: f64,
+    // 对比基准, 为空表示没有对比
+    pub benchmark: String,
+    pub benchmark_return: f64,
+    // 年化 alpha, 无风险利率按 0 计算
+    pub alpha: f64,
+    pub beta: f64,
+    // 超额收益的年化波动率
+    pub tracking_error: f64,
+    // 年化超额收益 / tracking_error
+    pub information_ratio: f64,
+    // 与基准收益率的相关系数
+    pub correlation: f64,
+}
+
+// 止损/止盈触发时的成交价
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum StopFill {
+    // 总是按止损/止盈价成交
+    StopPrice,
+    // 开盘跳空越过止损/止盈价时按开盘价成交
+    GapOpen,
+}
+
+// 市价单(buy/sell)的成交方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FillModel {
+    // 按下单时的价格(通常是当前 K 线收盘价)立即成交, 有未来函数
+    SameClose,
+    // 下一根 K 线开盘价
+    NextOpen,
+    // 下一根 K 线 (open+high+low+close)/4, 近似 VWAP
+    NextOhlcAvg,
+    // 下一根 K 线最差的价格: 买按最高价, 卖按最低价
+    WorstOfBar,
+}
+
+// 绩效对比的基准, 在回测时间窗口内买入持有
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Benchmark {
+    None,
+    // item 对比自身买入持有, 组合对比所有 item 等权买入持有
+    BuyAndHold,
+    // item 和组合都对比所有 item 等权买入持有
+    EqualWeight,
+    // item 和组合都对比指定币种买入持有, 从数据源读取与 item 相同周期的 K 线
+    Symbol(String),
+}
+
+// 跟踪止损, 随持仓期间的最高价(多)/最低价(空)移动
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TrailingStop {
+    None,
+    // 距最高/最低价 n 倍 ATR
+    Atr(f64),
+    // 距最高/最低价的比例, 如 0.05
+    Percent(f64),
 }
 
-#[derive(Debug, Clone)]
+// 止盈方式, 由 tp_method 解析, 如 "percent_0.23", "atr_5", "rr_2", "ladder_0.05:0.5,0.1:0.5"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TakeProfit {
+    // 距开仓价的比例
+    Percent(f64),
+    // 距开仓价 n 倍 ATR
+    Atr(f64),
+    // 止损距离的 n 倍, 需要开启止损
+    RiskReward(f64),
+    // 分批止盈: (距开仓价的比例, 平掉开仓仓位的比例), 最后一档平掉剩余全部仓位
+    Ladder(Vec<(f64, f64)>),
+}
+
+impl FromStr for TakeProfit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (method, value) = s
+            .split_once('_')
+            .ok_or_else(|| format!("invalid tp_method: {}", s))?;
+        let number = |v: &str| -> Result<f64, String> {
+            match v.trim().parse::<f64>() {
+                Ok(n) if n > 0.0 => Ok(n),
+                _ => Err(format!("invalid tp_method: {}", s)),
+            }
+        };
+        match method {
+            "percent" => Ok(TakeProfit::Percent(number(value)?)),
+            "atr" => Ok(TakeProfit::Atr(number(value)?)),
+            "rr" => Ok(TakeProfit::RiskReward(number(value)?)),
+            "ladder" => {
+                let mut steps = vec![];
+                for step in value.split(',') {
+                    let (percent, fraction) = step
+                        .split_once(':')
+                        .ok_or_else(|| format!("invalid tp_method: {}", s))?;
+                    steps.push((number(percent)?, number(fraction)?));
+                }
+                steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+                if steps.iter().map(|(_, fraction)| fraction).sum::<f64>() > 1.0 + 1e-9 {
+                    return Err(format!("tp_method ladder fractions over 1: {}", s));
+                }
+                Ok(TakeProfit::Ladder(steps))
+            }
+            _ => Err(format!("unknown tp_method: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StrategyParams {
     pub stg_name: String,
     pub window_length: i32,
@@ -192,9 +497,34 @@ pub struct StrategyParams {
     pub n_atr_sl: f64,
     pub is_tp: bool,
     pub n_atr_tp: f64,
+    // 止盈方式, 见 TakeProfit, 为空时按 n_atr_tp 倍 ATR
     pub tp_method: String,
+    pub stop_fill: StopFill,
+    pub fill_model: FillModel,
+    // 每根 K 线最多成交其成交量的比例, 剩余部分在之后的 K 线继续成交, 0 表示不限制
+    pub max_volume_participation: f64,
+    // 市价单/止损单成交的滑点, 限价单和止盈不计滑点
+    pub slippage: SlippageConfig,
+    // 滑点配置文件(json), 不为空时代替 slippage
+    pub slippage_file: String,
+    pub trailing_stop: TrailingStop,
+    pub benchmark: Benchmark,
     pub initial_capital: f64,
+    // 所有 item 共用 initial_capital, 每个 item 的 Equity 从 0 开始只记录该 item 的盈亏和资金占用
+    pub is_portfolio: bool,
     pub items_timestamp_start: HashMap<String, i64>,
     pub items_timestamp_end: HashMap<String, i64>,
     pub trading_fee: f64,
+    // maker/taker 及 VIP 等级费率, 为空时按 trading_fee 收取
+    pub fee_schedule: Option<FeeSchedule>,
+    // 手续费配置文件(json), 不为空时代替 fee_schedule
+    pub fee_file: String,
+    // data.binance.vision 下载的 K 线文件目录, 不为空时从这里读取行情, 代替 mongo
+    pub data_dir: String,
+    // mongo 数据的本地缓存目录, 为空则每次都查询 mongo
+    pub cache_dir: String,
+    // 回测结果输出目录, 每次运行写入 {results_dir}/{run_id}, 为空则不输出
+    pub results_dir: String,
+    // 保存回测结果的 mongo 数据库, 为空则不保存
+    pub results_db: String,
 }
@@ -0,0 +1,296 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::model::{Context, Profit};
+use super::stats::{self, EquityPoint};
+use crate::utils::common;
+use chrono::Datelike;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const WIDTH: f64 = 960.0;
+const HEIGHT: f64 = 260.0;
+const PAD_LEFT: f64 = 80.0;
+const PAD_RIGHT: f64 = 20.0;
+const PAD_TOP: f64 = 10.0;
+const PAD_BOTTOM: f64 = 30.0;
+const Y_TICKS: usize = 5;
+// 每条曲线最多画的点数, 1m 数据按间隔抽样
+const MAX_POINTS: usize = 2000;
+const COLOR_UP: &str = "#26a269";
+const COLOR_DOWN: &str = "#c01c28";
+
+const STYLE: &str = "body{font-family:sans-serif;margin:24px;color:#222}\
+h1{font-size:20px}h2{font-size:16px;margin-top:28px}\
+table{border-collapse:collapse;font-size:12px}td,th{border:1px solid #ddd;padding:4px 8px;text-align:right}\
+th:first-child,td:first-child{text-align:left}\
+svg{background:#fafafa;border:1px solid #eee}svg text{font-size:11px;fill:#555}";
+
+// 生成单个 html 文件的回测报告, 图表全部是内联 svg, 不依赖网络
+// 包括: 统计表, 组合权益曲线, 回撤, 月度收益热力图, 每个 item 的价格和开平仓标记
+pub fn render(title: &str, context: &Context, initial_capital: f64) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body><h1>{}</h1>",
+        escape(title),
+        STYLE,
+        escape(title)
+    );
+
+    html.push_str("<h2>Statistics</h2>");
+    html.push_str(&stats_table(&context.profits));
+
+    let curve = stats::portfolio_curve(context, initial_capital);
+    let equity: Vec<(i64, f64)> = curve.iter().map(|p| (p.timestamp, p.equity_value)).collect();
+    html.push_str("<h2>Equity</h2>");
+    html.push_str(&line_chart(&[("#1c71d8", &equity)], &[], number));
+
+    html.push_str("<h2>Drawdown</h2>");
+    html.push_str(&line_chart(&[(COLOR_DOWN, &drawdowns(&curve))], &[], |v| format!("{:.1}%", v*100.0)));
+
+    html.push_str("<h2>Monthly returns</h2>");
+    html.push_str(&monthly_table(&curve));
+
+    let mut items: Vec<&String> = context.candles.keys().collect();
+    items.sort();
+    for item in items {
+        let closes: Vec<(i64, f64)> = context.candles[item].iter().map(|c| (c.timestamp, c.close)).collect();
+        let mut markers = Vec::new();
+        for trade in context.trade_records.get(item).into_iter().flatten() {
+            let is_buy = trade.side == "buy";
+            markers.push(Marker {
+                timestamp: trade.time_open,
+                price: trade.price_open,
+                is_buy,
+                is_open: true,
+                label: format!("open {} {:.6} @ {}", trade.side, trade.size, trade.price_open),
+            });
+            if trade.time_close > 0 {
+                markers.push(Marker {
+                    timestamp: trade.time_close,
+                    price: trade.price_close,
+                    is_buy,
+                    is_open: false,
+                    label: format!("close {} @ {}, pnl {:.2}", trade.label_close, trade.price_close, trade.pnl - trade.fee),
+                });
+            }
+        }
+        let _ = write!(html, "<h2>{}</h2>", escape(item));
+        html.push_str(&line_chart(&[("#555", &closes)], &markers, number));
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+// 价格图上的开平仓标记, 开仓为三角形(买向上, 卖向下), 平仓为圆点
+struct Marker {
+    timestamp: i64,
+    price: f64,
+    // 开仓方向
+    is_buy: bool,
+    is_open: bool,
+    label: String,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn date(timestamp: i64) -> String {
+    common::timestamp_millis_to_datetime(timestamp).format("%Y-%m-%d").to_string()
+}
+
+// 相对前高的回撤, 0 到 -1
+fn drawdowns(curve: &[EquityPoint]) -> Vec<(i64, f64)> {
+    let mut peak = f64::MIN;
+    curve
+        .iter()
+        .map(|p| {
+            peak = peak.max(p.equity_value);
+            let drawdown = if peak > 0.0 { p.equity_value/peak - 1.0 } else { 0.0 };
+            (p.timestamp, drawdown)
+        })
+        .collect()
+}
+
+// 按间隔抽样, 保留最后一个点
+fn downsample(points: &[(i64, f64)]) -> Vec<(i64, f64)> {
+    let step = points.len()/MAX_POINTS + 1;
+    let mut sampled: Vec<(i64, f64)> = points.iter().step_by(step).copied().collect();
+    if let (Some(last), Some(sampled_last)) = (points.last(), sampled.last()) {
+        if last.0 != sampled_last.0 {
+            sampled.push(*last);
+        }
+    }
+    sampled
+}
+
+fn line_chart(series: &[(&str, &[(i64, f64)])], markers: &[Marker], format_value: impl Fn(f64) -> String) -> String {
+    let points = series.iter().flat_map(|(_, points)| points.iter().copied());
+    let (mut t0, mut t1, mut v0, mut v1) = (i64::MAX, i64::MIN, f64::MAX, f64::MIN);
+    for (t, v) in points.chain(markers.iter().map(|m| (m.timestamp, m.price))) {
+        t0 = t0.min(t);
+        t1 = t1.max(t);
+        v0 = v0.min(v);
+        v1 = v1.max(v);
+    }
+    if t0 >= t1 {
+        return "<p>no data</p>".to_string();
+    }
+    if v1 - v0 < 1e-12 {
+        v0 -= 1.0;
+        v1 += 1.0;
+    }
+    let x = |t: i64| PAD_LEFT + (t - t0) as f64/(t1 - t0) as f64*(WIDTH - PAD_LEFT - PAD_RIGHT);
+    let y = |v: f64| PAD_TOP + (v1 - v)/(v1 - v0)*(HEIGHT - PAD_TOP - PAD_BOTTOM);
+
+    let mut svg = String::new();
+    let _ = write!(svg, "<svg width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">", WIDTH, HEIGHT, WIDTH, HEIGHT);
+    for i in 0..=Y_TICKS {
+        let v = v0 + (v1 - v0)*i as f64/Y_TICKS as f64;
+        let _ = write!(
+            svg,
+            "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#e5e5e5\"/><text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+            PAD_LEFT, y(v), WIDTH - PAD_RIGHT, y(v), PAD_LEFT - 6.0, y(v) + 4.0, format_value(v)
+        );
+    }
+    let _ = write!(
+        svg,
+        "<text x=\"{:.1}\" y=\"{:.1}\">{}</text><text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+        PAD_LEFT, HEIGHT - 8.0, date(t0), WIDTH - PAD_RIGHT, HEIGHT - 8.0, date(t1)
+    );
+    for (color, points) in series {
+        let path: Vec<String> = downsample(points)
+            .iter()
+            .map(|(t, v)| format!("{:.1},{:.1}", x(*t), y(*v)))
+            .collect();
+        let _ = write!(svg, "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.2\" points=\"{}\"/>", color, path.join(" "));
+    }
+    for marker in markers {
+        let (mx, my) = (x(marker.timestamp), y(marker.price));
+        let color = if marker.is_buy { COLOR_UP } else { COLOR_DOWN };
+        // 三角形尖端朝向开仓方向
+        let tip = if marker.is_buy { -5.0 } else { 5.0 };
+        let shape = if marker.is_open {
+            format!("<polygon points=\"{:.1},{:.1} {:.1},{:.1} {:.1},{:.1}\" fill=\"{}\">", mx, my + tip, mx - 5.0, my - tip*0.8, mx + 5.0, my - tip*0.8, color)
+        } else {
+            format!("<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3.5\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\">", mx, my, color)
+        };
+        let tag = if marker.is_open { "polygon" } else { "circle" };
+        let _ = write!(svg, "{}<title>{} {}</title></{}>", shape, date(marker.timestamp), escape(&marker.label), tag);
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+// 统计表的一行: 名称和取值格式化
+type ProfitCell = fn(&Profit) -> String;
+
+fn percent(v: f64) -> String {
+    format!("{:.2}%", v*100.0)
+}
+
+fn number(v: f64) -> String {
+    format!("{:.2}", v)
+}
+
+fn stats_table(profits: &[Profit]) -> String {
+    let rows: Vec<(&str, ProfitCell)> = vec![
+        ("final equity", |p: &Profit| number(p.final_equity)),
+        ("total return", |p: &Profit| percent(p.total_return)),
+        ("CAGR", |p: &Profit| percent(p.cagr)),
+        ("volatility", |p: &Profit| percent(p.volatility)),
+        ("sharpe", |p: &Profit| number(p.sharpe)),
+        ("sortino", |p: &Profit| number(p.sortino)),
+        ("calmar", |p: &Profit| number(p.calmar)),
+        ("max drawdown", |p: &Profit| percent(p.max_drawdown)),
+        ("max drawdown days", |p: &Profit| format!("{:.1}", p.max_drawdown_duration as f64/86_400_000.0)),
+        ("trades", |p: &Profit| p.trades.to_string()),
+        ("win rate", |p: &Profit| percent(p.win_rate)),
+        ("profit factor", |p: &Profit| number(p.profit_factor)),
+        ("expectancy", |p: &Profit| number(p.expectancy)),
+        ("avg win", |p: &Profit| number(p.avg_win)),
+        ("avg loss", |p: &Profit| number(p.avg_loss)),
+        ("exposure time", |p: &Profit| percent(p.exposure_time)),
+        ("benchmark", |p: &Profit| escape(&p.benchmark)),
+        ("benchmark return", |p: &Profit| percent(p.benchmark_return)),
+        ("alpha", |p: &Profit| percent(p.alpha)),
+        ("beta", |p: &Profit| number(p.beta)),
+        ("tracking error", |p: &Profit| percent(p.tracking_error)),
+        ("information ratio", |p: &Profit| number(p.information_ratio)),
+        ("correlation", |p: &Profit| number(p.correlation)),
+    ];
+    let mut table = String::from("<table><tr><th></th>");
+    for profit in profits {
+        let _ = write!(table, "<th>{}</th>", escape(&profit.item));
+    }
+    table.push_str("</tr>");
+    for (name, value) in rows {
+        let _ = write!(table, "<tr><td>{}</td>", name);
+        for profit in profits {
+            let _ = write!(table, "<td>{}</td>", value(profit));
+        }
+        table.push_str("</tr>");
+    }
+    table.push_str("</table>");
+    table
+}
+
+// 每月收益 = 月末权益 / 上月末权益 - 1, 第一个月相对曲线起点
+fn monthly_table(curve: &[EquityPoint]) -> String {
+    let first = match curve.first() {
+        Some(point) => *point,
+        None => return "<p>no data</p>".to_string(),
+    };
+    // 月份 -> (月末权益, 起点之后的点数)
+    let mut month_end: BTreeMap<(i32, u32), (f64, usize)> = BTreeMap::new();
+    for point in curve {
+        let datetime = common::timestamp_millis_to_datetime(point.timestamp);
+        let entry = month_end.entry((datetime.year(), datetime.month())).or_insert((0.0, 0));
+        *entry = (point.equity_value, entry.1 + (point.timestamp > first.timestamp) as usize);
+    }
+    let mut previous = first.equity_value;
+    let mut returns: BTreeMap<i32, [Option<f64>; 12]> = BTreeMap::new();
+    for (i, ((year, month), (equity, count))) in month_end.into_iter().enumerate() {
+        // 回测开始时间通常在上个月末, 只有起点的月份不显示
+        if i == 0 && count == 0 {
+            continue;
+        }
+        if previous > 0.0 {
+            returns.entry(year).or_insert([None; 12])[month as usize - 1] = Some(equity/previous - 1.0);
+        }
+        previous = equity;
+    }
+    let max_abs = returns
+        .values()
+        .flat_map(|months| months.iter().flatten())
+        .fold(0.0_f64, |m, r| m.max(r.abs()));
+
+    let mut table = String::from("<table><tr><th>year</th>");
+    for month in ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec", "Year"] {
+        let _ = write!(table, "<th>{}</th>", month);
+    }
+    table.push_str("</tr>");
+    for (year, months) in returns {
+        let _ = write!(table, "<tr><td>{}</td>", year);
+        for r in months {
+            match r {
+                Some(r) => {
+                    // 颜色深浅按收益绝对值相对最大值
+                    let alpha = if max_abs > 0.0 { 0.15 + 0.75*r.abs()/max_abs } else { 0.0 };
+                    let rgb = if r >= 0.0 { "38,162,105" } else { "192,28,40" };
+                    let _ = write!(table, "<td style=\"background:rgba({},{:.2})\">{:.2}%</td>", rgb, alpha, r*100.0);
+                }
+                None => table.push_str("<td></td>"),
+            }
+        }
+        let year_return = months.iter().flatten().fold(1.0, |acc, r| acc*(1.0 + r)) - 1.0;
+        let _ = write!(table, "<td>{:.2}%</td></tr>", year_return*100.0);
+    }
+    table.push_str("</table>");
+    table
+}
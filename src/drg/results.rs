@@ -0,0 +1,247 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::model::Context;
+use super::report;
+use arrow::array::RecordBatch;
+use arrow::csv::WriterBuilder;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::json::ReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// 回测结果输出到 {root}/{run_id}/ 下, 每张表按固定的列和类型写一个 csv 和一个 parquet:
+// equities, portfolio, trades, orders, positions, profits, 以及 report.html
+#[derive(Debug, Clone)]
+pub struct ResultsWriter {
+    dir: PathBuf,
+}
+
+impl ResultsWriter {
+    pub fn new<P: AsRef<Path>>(root: P, run_id: &str) -> Self {
+        ResultsWriter { dir: root.as_ref().join(run_id) }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn write(&self, context: &Context) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let mut items: Vec<&String> = context.equities.keys().collect();
+        items.sort();
+        let equities: Vec<_> = items.iter().flat_map(|item| &context.equities[*item]).collect();
+        self.write_table("equities", equity_schema(), &equities)?;
+        self.write_table("portfolio", portfolio_schema(), &context.portfolio)?;
+
+        let mut items: Vec<&String> = context.trade_records.keys().collect();
+        items.sort();
+        let trades: Vec<_> = items.iter().flat_map(|item| &context.trade_records[*item]).collect();
+        self.write_table("trades", trade_schema(), &trades)?;
+        self.write_table("orders", order_schema(), &context.order_history)?;
+
+        let mut positions: Vec<_> = context.positions.values().collect();
+        positions.sort_by(|a, b| a.item.cmp(&b.item));
+        self.write_table("positions", position_schema(), &positions)?;
+        self.write_table("profits", profit_schema(), &context.profits)?;
+        Ok(())
+    }
+
+    pub fn write_report(&self, title: &str, context: &Context, initial_capital: f64) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join("report.html"), report::render(title, context, initial_capital))?;
+        Ok(())
+    }
+
+    // 按固定的 schema 把 rows 转成 RecordBatch, 写成 csv 和 parquet, 没有数据时也写出表头
+    fn write_table<T: Serialize>(&self, name: &str, schema: SchemaRef, rows: &[T]) -> Result<(), Box<dyn Error>> {
+        let mut decoder = ReaderBuilder::new(schema.clone()).build_decoder()?;
+        decoder.serialize(rows)?;
+        let batch = decoder.flush()?.unwrap_or_else(|| RecordBatch::new_empty(schema.clone()));
+
+        let csv_file = File::create(self.dir.join(format!("{}.csv", name)))?;
+        let mut writer = WriterBuilder::new().with_header(true).build(csv_file);
+        writer.write(&batch)?;
+
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let output = File::create(self.dir.join(format!("{}.parquet", name)))?;
+        let mut writer = ArrowWriter::try_new(output, schema, Some(props))?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+}
+
+fn schema(columns: &[(&str, DataType)]) -> SchemaRef {
+    Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|(name, data_type)| Field::new(*name, data_type.clone(), false))
+            .collect::<Vec<Field>>(),
+    ))
+}
+
+fn equity_schema() -> SchemaRef {
+    schema(&[
+        ("item", DataType::Utf8),
+        ("timestamp", DataType::Int64),
+        ("equity_value", DataType::Float64),
+        ("close_latest", DataType::Float64),
+        ("pos_size", DataType::Float64),
+        ("cash_aval", DataType::Float64),
+        ("unrealized_pnl", DataType::Float64),
+        ("realized_pnl", DataType::Float64),
+        ("exposure", DataType::Float64),
+    ])
+}
+
+fn portfolio_schema() -> SchemaRef {
+    schema(&[
+        ("timestamp", DataType::Int64),
+        ("equity_value", DataType::Float64),
+        ("cash_aval", DataType::Float64),
+        ("margin_used", DataType::Float64),
+        ("unrealized_pnl", DataType::Float64),
+        ("realized_pnl", DataType::Float64),
+        ("exposure", DataType::Float64),
+    ])
+}
+
+fn trade_schema() -> SchemaRef {
+    schema(&[
+        ("item", DataType::Utf8),
+        ("side", DataType::Utf8),
+        ("size", DataType::Float64),
+        ("price_open", DataType::Float64),
+        ("time_open", DataType::Int64),
+        ("price_close", DataType::Float64),
+        ("time_close", DataType::Int64),
+        ("label_close", DataType::Utf8),
+        ("slippage", DataType::Float64),
+        ("fee", DataType::Float64),
+        ("pnl", DataType::Float64),
+    ])
+}
+
+fn order_schema() -> SchemaRef {
+    schema(&[
+        ("id", DataType::UInt64),
+        ("item", DataType::Utf8),
+        ("price", DataType::Float64),
+        ("qty", DataType::Float64),
+        ("timestamp", DataType::Int64),
+        ("order_type", DataType::Utf8),
+        ("status", DataType::Utf8),
+        ("stop_price", DataType::Float64),
+        ("expire_at", DataType::Int64),
+        ("fill_price", DataType::Float64),
+        ("filled_qty", DataType::Float64),
+        ("slippage", DataType::Float64),
+        ("fee", DataType::Float64),
+    ])
+}
+
+fn position_schema() -> SchemaRef {
+    schema(&[
+        ("item", DataType::Utf8),
+        ("size", DataType::Float64),
+        ("price", DataType::Float64),
+        ("highest", DataType::Float64),
+        ("lowest", DataType::Float64),
+        ("stop_loss", DataType::Float64),
+        ("trailing_stop", DataType::Float64),
+        ("take_profit", DataType::Float64),
+        ("timestamp", DataType::Int64),
+    ])
+}
+
+fn profit_schema() -> SchemaRef {
+    schema(&[
+        ("item", DataType::Utf8),
+        ("initial_capital", DataType::Float64),
+        ("final_equity", DataType::Float64),
+        ("total_return", DataType::Float64),
+        ("cagr", DataType::Float64),
+        ("volatility", DataType::Float64),
+        ("sharpe", DataType::Float64),
+        ("sortino", DataType::Float64),
+        ("calmar", DataType::Float64),
+        ("max_drawdown", DataType::Float64),
+        ("max_drawdown_duration", DataType::Int64),
+        ("trades", DataType::UInt64),
+        ("win_rate", DataType::Float64),
+        ("profit_factor", DataType::Float64),
+        ("expectancy", DataType::Float64),
+        ("avg_win", DataType::Float64),
+        ("avg_loss", DataType::Float64),
+        ("exposure_time", DataType::Float64),
+        ("benchmark", DataType::Utf8),
+        ("benchmark_return", DataType::Float64),
+        ("alpha", DataType::Float64),
+        ("beta", DataType::Float64),
+        ("tracking_error", DataType::Float64),
+        ("information_ratio", DataType::Float64),
+        ("correlation", DataType::Float64),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drg::model::{Order, Profit};
+    use arrow::array::{Array, Float64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn read_parquet(path: &Path) -> (SchemaRef, Vec<RecordBatch>) {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap();
+        let schema = builder.schema().clone();
+        let batches = builder.build().unwrap().map(|b| b.unwrap()).collect();
+        (schema, batches)
+    }
+
+    #[test]
+    fn typed_tables_written_even_when_empty() {
+        let root = std::env::temp_dir().join(format!("results_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let mut context = Context::new();
+        context.order_history.push(Order::market("BTCUSDT_1d", 1.0, 100.0, 0));
+        context.push_profit(Profit {
+            item: "portfolio".to_string(),
+            profit_factor: f64::INFINITY,
+            ..Default::default()
+        });
+        let writer = ResultsWriter::new(&root, "run");
+        writer.write(&context).unwrap();
+
+        // 没有交易时也有表头和 parquet
+        let header = fs::read_to_string(writer.dir().join("trades.csv")).unwrap();
+        assert_eq!(header.trim(), "item,side,size,price_open,time_open,price_close,time_close,label_close,slippage,fee,pnl");
+        let (schema, batches) = read_parquet(&writer.dir().join("trades.parquet"));
+        assert_eq!(schema, trade_schema());
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 0);
+        assert!(writer.dir().join("portfolio.parquet").exists());
+
+        // 列类型不随数据变化
+        let (schema, batches) = read_parquet(&writer.dir().join("profits.parquet"));
+        assert_eq!(schema, profit_schema());
+        let profit_factor = batches[0].column_by_name("profit_factor").unwrap();
+        let profit_factor = profit_factor.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(profit_factor.len(), 1);
+        assert!(profit_factor.value(0).is_infinite());
+        let (schema, _) = read_parquet(&writer.dir().join("orders.parquet"));
+        assert_eq!(schema, order_schema());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::model::Candle;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// 成交滑点, 买入成交价上移, 卖出下移
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum Slippage {
+    #[default]
+    None,
+    // 固定万分比
+    Bps { bps: f64 },
+    // 固定 ticks 个最小价格变动
+    Ticks { ticks: f64, tick_size: f64 },
+    // ATR 的比例
+    Atr { ratio: f64 },
+    // 冲击成本 impact*sqrt(成交量/K 线成交量), 下单量相对 K 线成交量越大滑点越大
+    Volume { impact: f64 },
+}
+
+impl Slippage {
+    // 每单位的滑点价差, 没有 ATR 或成交量时按 0 处理
+    pub fn price_offset(&self, price: f64, qty: f64, atr: Option<f64>, candle: Option<&Candle>) -> f64 {
+        match *self {
+            Slippage::None => 0.0,
+            Slippage::Bps { bps } => price*bps/10000.0,
+            Slippage::Ticks { ticks, tick_size } => ticks*tick_size,
+            Slippage::Atr { ratio } => atr.map(|atr| atr*ratio).unwrap_or(0.0),
+            Slippage::Volume { impact } => match candle {
+                Some(candle) if candle.volume > 0.0 => price*impact*(qty.abs()/candle.volume).sqrt(),
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+// 按币种配置滑点, 没有配置的币种使用 default
+// {"default": {"model": "bps", "bps": 2.0}, "symbols": {"BTCUSDT": {"model": "ticks", "ticks": 1, "tick_size": 0.1}}}
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SlippageConfig {
+    #[serde(default)]
+    pub default: Slippage,
+    #[serde(default)]
+    pub symbols: HashMap<String, Slippage>,
+}
+
+impl SlippageConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn for_symbol(&self, symbol: &str) -> Slippage {
+        self.symbols.get(symbol).copied().unwrap_or(self.default)
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::{blocking_stream, CandleStream, DataSource, SourceError};
+use crate::drg::model::Candle;
+use crate::utils::common;
+use chrono::{Datelike, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// 读取 data.binance.vision 下载的 kline 文件, 目录结构不限, 例如
+// {root}/spot/monthly/klines/BTCUSDT/1d/BTCUSDT-1d-2024-01.zip
+// {root}/spot/daily/klines/BTCUSDT/1m/BTCUSDT-1m-2024-01-01.csv
+// 列: open_time,open,high,low,close,volume,close_time,...
+#[derive(Debug, Clone)]
+pub struct BinanceCsvSource {
+    root: PathBuf,
+}
+
+impl BinanceCsvSource {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        BinanceCsvSource { root: root.into() }
+    }
+
+    // 查找 {symbol}-{interval}-*.zip/csv
+    fn find_files(&self, symbol: &str, interval: &str) -> Vec<PathBuf> {
+        let root = glob::Pattern::escape(&self.root.to_string_lossy());
+        let mut files = Vec::new();
+        for ext in ["zip", "csv"] {
+            let pattern = format!("{}/**/{}-{}-*.{}", root, symbol, file_interval(interval), ext);
+            match glob::glob(&pattern) {
+                Ok(paths) => files.extend(paths.filter_map(|p| p.ok())),
+                Err(e) => log::error!("glob {} error: {:?}", pattern, e),
+            }
+        }
+        // 按文件的开始日期排序, 同一天开始的月度文件排在日度文件前
+        files.sort_by_key(|path| {
+            file_period(path, symbol, interval).map(|(start, end)| (start, std::cmp::Reverse(end)))
+        });
+        files
+    }
+}
+
+// 文件名中的周期, 月线为 1mo 而不是 1M
+fn file_interval(interval: &str) -> &str {
+    if interval == "1M" { "1mo" } else { interval }
+}
+
+// 从文件名的日期后缀(2024-01 或 2024-01-01)得到 [开始, 结束) 毫秒时间
+fn file_period(path: &Path, symbol: &str, interval: &str) -> Option<(i64, i64)> {
+    let stem = path.file_stem()?.to_str()?;
+    let date = stem.strip_prefix(&format!("{}-{}-", symbol, file_interval(interval)))?;
+    let (start, end) = match date.len() {
+        10 => {
+            let start = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+            (start, start.succ_opt()?)
+        }
+        7 => {
+            let start = NaiveDate::parse_from_str(&format!("{}-01", date), "%Y-%m-%d").ok()?;
+            let end = if start.month() == 12 {
+                NaiveDate::from_ymd_opt(start.year() + 1, 1, 1)?
+            } else {
+                NaiveDate::from_ymd_opt(start.year(), start.month() + 1, 1)?
+            };
+            (start, end)
+        }
+        _ => return None,
+    };
+    let millis = |d: NaiveDate| Utc.from_utc_datetime(&d.and_time(NaiveTime::MIN)).timestamp_millis();
+    Some((millis(start), millis(end)))
+}
+
+fn parse_rows<R: Read>(reader: R, symbol: &str, interval: &str, name: &str) -> Vec<Candle> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let mut candles = Vec::new();
+    for (i, result) in csv_reader.records().enumerate() {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                log::error!("{} read error: {:?}", name, e);
+                continue;
+            }
+        };
+        let field = |idx: usize| record.get(idx).map(|s| s.trim());
+        let timestamp = match field(0).and_then(|s| s.parse::<i64>().ok()) {
+            Some(t) => common::normalize_timestamp_millis(t),
+            None => {
+                // 首行可能是表头
+                if i > 0 {
+                    log::error!("{} line {}: bad open_time {:?}", name, i + 1, field(0));
+                }
+                continue;
+            }
+        };
+        let values: Vec<f64> = (1..6)
+            .filter_map(|idx| field(idx).and_then(|s| s.parse::<f64>().ok()))
+            .collect();
+        if values.len() != 5 {
+            log::error!("{} line {}: bad ohlcv", name, i + 1);
+            continue;
+        }
+        candles.push(Candle {
+            symbol: symbol.to_string(),
+            timestamp,
+            open: values[0],
+            high: values[1],
+            low: values[2],
+            close: values[3],
+            volume: values[4],
+            interval: interval.to_string(),
+        });
+    }
+    candles
+}
+
+fn read_file(path: &Path, symbol: &str, interval: &str) -> Result<Vec<Candle>, SourceError> {
+    let name = path.to_string_lossy();
+    let file = File::open(path)?;
+    if path.extension().map(|e| e == "zip").unwrap_or(false) {
+        let mut archive = zip::ZipArchive::new(file)?;
+        let mut candles = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            if entry.name().ends_with(".csv") {
+                candles.extend(parse_rows(entry, symbol, interval, &name));
+            }
+        }
+        Ok(candles)
+    } else {
+        Ok(parse_rows(file, symbol, interval, &name))
+    }
+}
+
+impl DataSource for BinanceCsvSource {
+    fn stream_candles(
+        &self,
+        symbol: &str,
+        interval: &str,
+        timestamp_start: i64,
+        timestamp_end: i64,
+    ) -> CandleStream {
+        let files = self.find_files(symbol, interval);
+        let symbol = symbol.to_string();
+        let interval = interval.to_string();
+        // 每次只读一个文件, 月度和日度文件可能重叠, 跳过已输出的时间
+        blocking_stream(move |sender| {
+            let mut last_timestamp = timestamp_start;
+            for path in files {
+                // 按文件名日期跳过时间范围之外的文件
+                if let Some((period_start, period_end)) = file_period(&path, &symbol, &interval) {
+                    if period_end <= last_timestamp
+                        || (timestamp_end > 0 && period_start >= timestamp_end)
+                    {
+                        continue;
+                    }
+                }
+                let candles = match read_file(&path, &symbol, &interval) {
+                    Ok(candles) => candles,
+                    Err(e) => {
+                        let e = format!("read {} error: {:?}", path.to_string_lossy(), e);
+                        if sender.blocking_send(Err(e.into())).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                for candle in candles {
+                    if candle.timestamp <= last_timestamp
+                        || (timestamp_end > 0 && candle.timestamp >= timestamp_end)
+                    {
+                        continue;
+                    }
+                    last_timestamp = candle.timestamp;
+                    if sender.blocking_send(Ok(candle)).is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use std::fs;
+    use std::io::Write;
+
+    // 2024-01-01 00:00:00 UTC
+    const JAN_1: i64 = 1704067200000;
+    const DAY: i64 = 24*60*60*1000;
+
+    fn row(timestamp: i64, close: f64) -> String {
+        format!("{},{},{},{},{},10.0,{},0,0,0,0,0\n", timestamp, close, close + 1.0, close - 1.0, close, timestamp + DAY - 1)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("binance_csv_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_zip(path: &Path, name: &str, text: &str) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+        zip.write_all(text.as_bytes()).unwrap();
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn parse_rows_with_header_and_timestamp_units() {
+        // 秒/毫秒/微秒的 open_time 都转为毫秒, 表头和坏行跳过
+        let text = format!(
+            "open_time,open,high,low,close,volume,close_time\n{}{}{}bad,1,2,3\n",
+            row(JAN_1/1000, 100.0),
+            row(JAN_1 + DAY, 101.0),
+            row((JAN_1 + 2*DAY)*1000, 102.0),
+        );
+        let candles = parse_rows(text.as_bytes(), "BTCUSDT", "1d", "test");
+        let timestamps: Vec<i64> = candles.iter().map(|c| c.timestamp).collect();
+        assert_eq!(timestamps, vec![JAN_1, JAN_1 + DAY, JAN_1 + 2*DAY]);
+        let candle = &candles[1];
+        assert_eq!((candle.open, candle.high, candle.low, candle.close, candle.volume), (101.0, 102.0, 100.0, 101.0, 10.0));
+        assert_eq!((candle.symbol.as_str(), candle.interval.as_str()), ("BTCUSDT", "1d"));
+    }
+
+    #[test]
+    fn file_period_from_name() {
+        let period = |name: &str, interval: &str| file_period(Path::new(name), "BTCUSDT", interval);
+        assert_eq!(period("BTCUSDT-1d-2024-01.zip", "1d"), Some((JAN_1, JAN_1 + 31*DAY)));
+        assert_eq!(period("BTCUSDT-1h-2024-01-02.csv", "1h"), Some((JAN_1 + DAY, JAN_1 + 2*DAY)));
+        // 12 月的文件到下一年
+        let dec_1 = JAN_1 - 31*DAY;
+        assert_eq!(period("BTCUSDT-1d-2023-12.zip", "1d"), Some((dec_1, JAN_1)));
+        // 月线的文件名周期为 1mo
+        assert_eq!(period("BTCUSDT-1mo-2024-01.zip", "1M"), Some((JAN_1, JAN_1 + 31*DAY)));
+        assert_eq!(period("BTCUSDT-1M-2024-01.zip", "1M"), None);
+        assert_eq!(period("ETHUSDT-1d-2024-01.zip", "1d"), None);
+    }
+
+    #[test]
+    fn read_zipped_csv() {
+        let dir = test_dir("zip");
+        let path = dir.join("BTCUSDT-1d-2024-01.zip");
+        write_zip(&path, "BTCUSDT-1d-2024-01.csv", &format!("{}{}", row(JAN_1, 100.0), row(JAN_1 + DAY, 101.0)));
+        let candles = read_file(&path, "BTCUSDT", "1d").unwrap();
+        assert_eq!(candles.iter().map(|c| c.close).collect::<Vec<_>>(), vec![100.0, 101.0]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn overlapping_monthly_and_daily_files() {
+        let dir = test_dir("overlap");
+        let monthly = dir.join("spot/monthly/klines/BTCUSDT/1d");
+        let daily = dir.join("spot/daily/klines/BTCUSDT/1d");
+        fs::create_dir_all(&monthly).unwrap();
+        fs::create_dir_all(&daily).unwrap();
+        // 月度文件有 1-3 日, 日度文件重复了 3 日并补充 4 日
+        let text: String = (0..3).map(|i| row(JAN_1 + i*DAY, 100.0 + i as f64)).collect();
+        write_zip(&monthly.join("BTCUSDT-1d-2024-01.zip"), "BTCUSDT-1d-2024-01.csv", &text);
+        fs::write(daily.join("BTCUSDT-1d-2024-01-03.csv"), row(JAN_1 + 2*DAY, 102.0)).unwrap();
+        fs::write(daily.join("BTCUSDT-1d-2024-01-04.csv"), row(JAN_1 + 3*DAY, 103.0)).unwrap();
+
+        let source = BinanceCsvSource::new(&dir);
+        let timestamps: Vec<i64> = source
+            .stream_candles("BTCUSDT", "1d", 0, 0)
+            .map(|c| c.unwrap().timestamp)
+            .collect()
+            .await;
+        assert_eq!(timestamps, (0..4).map(|i| JAN_1 + i*DAY).collect::<Vec<_>>());
+
+        // 月线文件名为 1mo, K 线的周期仍为 1M
+        fs::write(monthly.join("BTCUSDT-1mo-2024-01.csv"), row(JAN_1, 100.0)).unwrap();
+        let candles: Vec<Candle> = source
+            .stream_candles("BTCUSDT", "1M", 0, 0)
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(candles.len(), 1);
+        assert_eq!(candles[0].interval, "1M");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
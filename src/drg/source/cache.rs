@@ -0,0 +1,258 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::{CandleStream, DataSource, ParquetStore, SourceError};
+use crate::utils::common;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// 缓存格式变化时加一, 旧缓存会被自动丢弃
+const CACHE_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+
+// 已缓存的时间范围, 与查询一致为开区间: timestamp_start < timestamp < timestamp_end
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheManifest {
+    version: u32,
+    symbol: String,
+    interval: String,
+    timestamp_start: i64,
+    timestamp_end: i64,
+    updated_at: i64,
+}
+
+// 在任意数据源(通常是 mongo)前加一层本地 parquet 缓存,
+// 按 {root}/{symbol}/{interval} 保存, 之后只向数据源补取缺少的头部和尾部
+#[derive(Debug, Clone)]
+pub struct CachedSource {
+    inner: Arc<dyn DataSource>,
+    root: PathBuf,
+    store: ParquetStore,
+}
+
+impl CachedSource {
+    pub fn new<P: Into<PathBuf>>(inner: Arc<dyn DataSource>, root: P) -> Self {
+        let root = root.into();
+        CachedSource {
+            inner,
+            store: ParquetStore::new(root.clone()),
+            root,
+        }
+    }
+
+    fn manifest_path(&self, symbol: &str, interval: &str) -> PathBuf {
+        self.root.join(symbol).join(interval).join(MANIFEST_FILE)
+    }
+
+    fn read_manifest(&self, symbol: &str, interval: &str) -> Option<CacheManifest> {
+        let text = fs::read_to_string(self.manifest_path(symbol, interval)).ok()?;
+        match serde_json::from_str::<CacheManifest>(&text) {
+            Ok(manifest) if manifest.version == CACHE_VERSION => Some(manifest),
+            _ => {
+                log::warn!("cache {}_{} outdated, rebuild", symbol, interval);
+                None
+            }
+        }
+    }
+
+    fn write_manifest(&self, manifest: &CacheManifest) -> Result<(), SourceError> {
+        let path = self.manifest_path(&manifest.symbol, &manifest.interval);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(manifest)?)?;
+        Ok(())
+    }
+
+    // 删除 symbol/interval 的缓存, 下次查询重新从数据源加载
+    pub fn invalidate(&self, symbol: &str, interval: &str) {
+        let dir = self.root.join(symbol).join(interval);
+        if dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&dir) {
+                log::error!("remove cache {:?} error: {:?}", dir, e);
+            }
+        }
+    }
+
+    // 从数据源取 (timestamp_start, timestamp_end) 写入缓存, 返回取到的条数
+    async fn fetch(&self, symbol: &str, interval: &str, timestamp_start: i64, timestamp_end: i64) -> Result<usize, SourceError> {
+        let candles = self.inner.stream_candles(symbol, interval, timestamp_start, timestamp_end);
+        let count = self.store.write_stream(candles).await?;
+        log::info!("cache {}_{} fetch {} candles", symbol, interval, count);
+        Ok(count)
+    }
+
+    // 补齐缓存使其覆盖请求的范围
+    async fn sync(&self, symbol: &str, interval: &str, timestamp_start: i64, timestamp_end: i64) -> Result<(), SourceError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let request_end = if timestamp_end <= 0 || timestamp_end > now { now } else { timestamp_end };
+        // 取完后整个请求范围都算已缓存, 只有还没走完的 K 线不算, 下次会重新获取
+        let covered_end = request_end.min(now - common::interval_millis(interval).unwrap_or(0));
+
+        let manifest = match self.read_manifest(symbol, interval) {
+            Some(manifest) => manifest,
+            None => {
+                self.invalidate(symbol, interval);
+                // 数据源没有数据(如集合还没导入)时不写 manifest, 下次重新获取
+                if self.fetch(symbol, interval, timestamp_start, request_end).await? == 0 {
+                    return Ok(());
+                }
+                return self.write_manifest(&CacheManifest {
+                    version: CACHE_VERSION,
+                    symbol: symbol.to_string(),
+                    interval: interval.to_string(),
+                    timestamp_start,
+                    timestamp_end: covered_end,
+                    updated_at: now,
+                });
+            }
+        };
+        if timestamp_start >= manifest.timestamp_start && request_end <= manifest.timestamp_end {
+            return Ok(());
+        }
+        let mut updated = manifest.clone();
+        if timestamp_start < manifest.timestamp_start {
+            // 取 (timestamp_start, manifest.timestamp_start], 与已缓存范围连成一段
+            self.fetch(symbol, interval, timestamp_start, manifest.timestamp_start + 1).await?;
+            updated.timestamp_start = timestamp_start;
+        }
+        if request_end > manifest.timestamp_end {
+            // 取 [manifest.timestamp_end, request_end)
+            self.fetch(symbol, interval, manifest.timestamp_end - 1, request_end).await?;
+            updated.timestamp_end = covered_end.max(manifest.timestamp_end);
+        }
+        updated.updated_at = now;
+        self.write_manifest(&updated)
+    }
+}
+
+impl DataSource for CachedSource {
+    fn stream_candles(
+        &self,
+        symbol: &str,
+        interval: &str,
+        timestamp_start: i64,
+        timestamp_end: i64,
+    ) -> CandleStream {
+        let cache = self.clone();
+        let (symbol, interval) = (symbol.to_string(), interval.to_string());
+        let candles = async move {
+            if let Err(e) = cache.sync(&symbol, &interval, timestamp_start, timestamp_end).await {
+                log::error!("cache {}_{} sync error: {:?}", symbol, interval, e);
+                cache.invalidate(&symbol, &interval);
+                return cache.inner.stream_candles(&symbol, &interval, timestamp_start, timestamp_end);
+            }
+            let stream = cache.store.stream_candles(&symbol, &interval, timestamp_start, timestamp_end);
+            stream
+                .inspect_err(move |e| {
+                    // 缓存文件损坏, 丢弃后下次重新从数据源加载
+                    log::error!("cache {}_{} read error: {:?}", symbol, interval, e);
+                    cache.invalidate(&symbol, &interval);
+                })
+                .boxed()
+        };
+        stream::once(candles).flatten().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drg::model::Candle;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    const HOUR: i64 = 60*60*1000;
+
+    // 内存数据源, 可在测试中途追加数据, 记录被查询的次数
+    #[derive(Debug, Default)]
+    struct MemorySource {
+        candles: Mutex<Vec<Candle>>,
+        calls: AtomicUsize,
+    }
+
+    impl DataSource for MemorySource {
+        fn stream_candles(&self, _symbol: &str, _interval: &str, timestamp_start: i64, timestamp_end: i64) -> CandleStream {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let candles: Vec<Result<Candle, SourceError>> = self
+                .candles
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| c.timestamp > timestamp_start && (timestamp_end <= 0 || c.timestamp < timestamp_end))
+                .cloned()
+                .map(Ok)
+                .collect();
+            stream::iter(candles).boxed()
+        }
+    }
+
+    fn candle(timestamp: i64) -> Candle {
+        Candle {
+            symbol: "BTCUSDT".to_string(),
+            timestamp,
+            open: 1.0,
+            high: 1.0,
+            low: 1.0,
+            close: 1.0,
+            volume: 1.0,
+            interval: "1h".to_string(),
+        }
+    }
+
+    async fn read(cache: &CachedSource, timestamp_start: i64, timestamp_end: i64) -> Vec<i64> {
+        cache
+            .stream_candles("BTCUSDT", "1h", timestamp_start, timestamp_end)
+            .map(|c| c.unwrap().timestamp)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn repeated_range_served_from_cache() {
+        let root = std::env::temp_dir().join(format!("cache_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let source = Arc::new(MemorySource::default());
+        let cache = CachedSource::new(source.clone(), root.clone());
+        let calls = || source.calls.load(Ordering::SeqCst);
+        // 与回测一样, 请求范围比数据宽
+        let (start, end) = (HOUR - 1000, 10*HOUR + 1000);
+
+        // 数据源还没有数据, 不写 manifest
+        assert!(read(&cache, start, end).await.is_empty());
+        assert!(cache.read_manifest("BTCUSDT", "1h").is_none());
+        assert_eq!(calls(), 1);
+
+        // 取到数据后整个请求范围都已缓存, 重复查询不再访问数据源
+        source.candles.lock().unwrap().extend((1..=10).map(|i| candle(i*HOUR)));
+        assert_eq!(read(&cache, start, end).await.len(), 10);
+        let manifest = cache.read_manifest("BTCUSDT", "1h").unwrap();
+        assert_eq!((manifest.timestamp_start, manifest.timestamp_end), (start, end));
+        assert_eq!(calls(), 2);
+        for _ in 0..2 {
+            assert_eq!(read(&cache, start, end).await.len(), 10);
+        }
+        assert_eq!(calls(), 2);
+
+        // 范围变宽时只补取尾部, 之后同样不再访问数据源
+        source.candles.lock().unwrap().extend((11..=20).map(|i| candle(i*HOUR)));
+        let timestamps = read(&cache, start, 20*HOUR + 1000).await;
+        assert_eq!(timestamps, (1..=20).map(|i| i*HOUR).collect::<Vec<_>>());
+        assert_eq!(calls(), 3);
+        assert_eq!(read(&cache, start, 20*HOUR + 1000).await.len(), 20);
+        assert_eq!(calls(), 3);
+
+        // invalidate 后重新获取
+        cache.invalidate("BTCUSDT", "1h");
+        assert_eq!(read(&cache, start, end).await.len(), 10);
+        assert_eq!(calls(), 4);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::model::Candle;
+use futures::stream::{BoxStream, StreamExt};
+use std::error::Error;
+use tokio::sync::mpsc;
+
+pub mod binance_csv;
+pub mod cache;
+pub mod mongo;
+pub mod parquet_store;
+
+pub use binance_csv::BinanceCsvSource;
+pub use cache::CachedSource;
+pub use mongo::MongoSource;
+pub use parquet_store::ParquetStore;
+
+pub type SourceError = Box<dyn Error + Send + Sync>;
+pub type CandleStream = BoxStream<'static, Result<Candle, SourceError>>;
+
+// 文件类数据源在阻塞线程读取时的缓冲条数
+const STREAM_BUFFER: usize = 1024;
+
+// 行情数据源, BrokerLocal 通过它加载 K 线, 与具体存储(mongo/文件/内存)解耦
+pub trait DataSource: Send + Sync + std::fmt::Debug {
+    // 返回 timestamp_start < timestamp < timestamp_end 的 K 线流, 按时间升序;
+    // timestamp_end <= 0 表示不限结束时间
+    fn stream_candles(
+        &self,
+        symbol: &str,
+        interval: &str,
+        timestamp_start: i64,
+        timestamp_end: i64,
+    ) -> CandleStream;
+}
+
+// 在阻塞线程中读取(文件等), 通过有界 channel 逐条输出, 消费者停止读取时读取线程随之结束
+pub fn blocking_stream<F>(read: F) -> CandleStream
+where
+    F: FnOnce(mpsc::Sender<Result<Candle, SourceError>>) + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    tokio::task::spawn_blocking(move || read(sender));
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+    .boxed()
+}
@@ -0,0 +1,95 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::{CandleStream, DataSource, SourceError};
+use crate::drg::model::{Candle, CandleHelper};
+use crate::utils::db::ClientMongo;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use mongodb::bson::{self, doc, Document};
+
+fn to_candle(
+    symbol: &str,
+    result: Result<Document, mongodb::error::Error>,
+) -> Option<Result<Candle, SourceError>> {
+    let doc = match result {
+        Ok(doc) => doc,
+        Err(e) => return Some(Err(e.into())),
+    };
+    // 尝试从Document中反序列化CandleHelper
+    let candle_helper: CandleHelper = match bson::from_document(doc) {
+        Ok(helper) => helper,
+        Err(e) => {
+            log::error!("{:?}", e);
+            return None;
+        } // 如果反序列化失败，跳过这个文档
+    };
+
+    // 手动转换CandleHelper为Candle
+    Some(Ok(Candle {
+        symbol: symbol.to_string(),
+        timestamp: candle_helper.timestamp,
+        open: candle_helper.open,
+        high: candle_helper.high,
+        low: candle_helper.low,
+        close: candle_helper.close,
+        volume: candle_helper.volume,
+        interval: candle_helper.interval,
+    }))
+}
+
+// 从 mongo 的 {symbol}_{interval} 集合读取 K 线
+#[derive(Debug, Clone)]
+pub struct MongoSource {
+    client: ClientMongo,
+}
+
+impl MongoSource {
+    pub fn new(client: ClientMongo) -> Self {
+        MongoSource { client }
+    }
+    pub fn with_db_name(db_name: String) -> Self {
+        Self::new(ClientMongo::with_db_name(db_name))
+    }
+    pub fn db_name(&self) -> &str {
+        self.client.db_name()
+    }
+}
+
+impl Default for MongoSource {
+    fn default() -> Self {
+        Self::with_db_name("cryptodb".to_string())
+    }
+}
+
+impl DataSource for MongoSource {
+    fn stream_candles(
+        &self,
+        symbol: &str,
+        interval: &str,
+        timestamp_start: i64,
+        timestamp_end: i64,
+    ) -> CandleStream {
+        let label = format!("{}_{}", symbol, interval);
+        let mut filter = doc! {"_id": {"$gt": timestamp_start}};
+        if timestamp_end > 0 && timestamp_end > timestamp_start {
+            let end_condition = doc! {"_id": {"$lt": timestamp_end}};
+            let and_filter = doc! {"$and": vec![filter, end_condition]};
+            filter = and_filter;
+        }
+        let client = self.client.clone();
+        let symbol = symbol.to_string();
+        let candles = async move {
+            match client.records_stream(&label, Some(filter), None, None, None).await {
+                Ok(cursor) => cursor
+                    .filter_map(move |result| future::ready(to_candle(&symbol, result)))
+                    .boxed(),
+                Err(e) => stream::once(future::ready(Err(SourceError::from(e)))).boxed(),
+            }
+        };
+        stream::once(candles).flatten().boxed()
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::{blocking_stream, CandleStream, DataSource, SourceError};
+use crate::drg::model::Candle;
+use crate::utils::common;
+use arrow::array::{Array, ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow::compute::kernels::cmp::{gt, lt};
+use arrow::compute::and;
+use arrow::datatypes::{DataType, Field, Schema};
+use chrono::NaiveDate;
+use futures::stream::StreamExt;
+use parquet::arrow::arrow_reader::{
+    ArrowPredicateFn, ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder, RowFilter,
+};
+use parquet::arrow::{ArrowWriter, ProjectionMask};
+use parquet::basic::Compression;
+use parquet::errors::{ParquetError, Result};
+use parquet::file::properties::WriterProperties;
+use parquet::file::statistics::Statistics;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const COL_TIMESTAMP: usize = 2;
+// 每个 row group 的行数, 1m 数据约 5.7 天, 便于按统计信息跳过
+const ROW_GROUP_SIZE: usize = 8192;
+
+// 按 {root}/{symbol}/{interval}/{YYYY-MM}.parquet 分区保存的 K 线
+#[derive(Debug, Clone)]
+pub struct ParquetStore {
+    root: PathBuf,
+}
+
+fn candle_schema() -> Arc<Schema> {
+    Arc::new(Schema::new(vec![
+        Field::new("symbol", DataType::Utf8, false),
+        Field::new("interval", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("open", DataType::Float64, false),
+        Field::new("high", DataType::Float64, false),
+        Field::new("low", DataType::Float64, false),
+        Field::new("close", DataType::Float64, false),
+        Field::new("volume", DataType::Float64, false),
+    ]))
+}
+
+fn candles_to_batch(candles: &[Candle]) -> Result<RecordBatch> {
+    let f64_col = |f: fn(&Candle) -> f64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(candles.iter().map(f)))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(candles.iter().map(|c| c.symbol.as_str()))),
+        Arc::new(StringArray::from_iter_values(candles.iter().map(|c| c.interval.as_str()))),
+        Arc::new(Int64Array::from_iter_values(candles.iter().map(|c| c.timestamp))),
+        f64_col(|c| c.open),
+        f64_col(|c| c.high),
+        f64_col(|c| c.low),
+        f64_col(|c| c.close),
+        f64_col(|c| c.volume),
+    ];
+    Ok(RecordBatch::try_new(candle_schema(), columns)?)
+}
+
+fn batch_to_candles(batch: &RecordBatch) -> Result<Vec<Candle>> {
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+        batch
+            .column_by_name(name)
+            .and_then(|c| c.as_any().downcast_ref::<T>())
+            .ok_or_else(|| ParquetError::General(format!("bad column {}", name)))
+    }
+    let symbols = column::<StringArray>(batch, "symbol")?;
+    let intervals = column::<StringArray>(batch, "interval")?;
+    let timestamps = column::<Int64Array>(batch, "timestamp")?;
+    let opens = column::<Float64Array>(batch, "open")?;
+    let highs = column::<Float64Array>(batch, "high")?;
+    let lows = column::<Float64Array>(batch, "low")?;
+    let closes = column::<Float64Array>(batch, "close")?;
+    let volumes = column::<Float64Array>(batch, "volume")?;
+    Ok((0..batch.num_rows())
+        .map(|i| Candle {
+            symbol: symbols.value(i).to_string(),
+            timestamp: timestamps.value(i),
+            open: opens.value(i),
+            high: highs.value(i),
+            low: lows.value(i),
+            close: closes.value(i),
+            volume: volumes.value(i),
+            interval: intervals.value(i).to_string(),
+        })
+        .collect())
+}
+
+// 读取一个分区文件中 timestamp_start < timestamp < timestamp_end 的 K 线,
+// 先用 row group 统计信息跳过, 再用 row filter 只解码满足条件的行
+fn partition_reader(
+    path: &Path,
+    timestamp_start: i64,
+    timestamp_end: i64,
+) -> Result<ParquetRecordBatchReader> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let row_groups: Vec<usize> = builder
+        .metadata()
+        .row_groups()
+        .iter()
+        .enumerate()
+        .filter(|(_, rg)| match rg.column(COL_TIMESTAMP).statistics() {
+            Some(Statistics::Int64(s)) if s.has_min_max_set() => {
+                *s.max() > timestamp_start && (timestamp_end <= 0 || *s.min() < timestamp_end)
+            }
+            _ => true,
+        })
+        .map(|(i, _)| i)
+        .collect();
+    let projection = ProjectionMask::roots(builder.parquet_schema(), [COL_TIMESTAMP]);
+    let predicate = ArrowPredicateFn::new(projection, move |batch: RecordBatch| {
+        let timestamps = batch.column(0);
+        let mask = gt(timestamps, &Int64Array::new_scalar(timestamp_start))?;
+        if timestamp_end > 0 {
+            and(&mask, &lt(timestamps, &Int64Array::new_scalar(timestamp_end))?)
+        } else {
+            Ok(mask)
+        }
+    });
+    builder
+        .with_row_groups(row_groups)
+        .with_row_filter(RowFilter::new(vec![Box::new(predicate)]))
+        .build()
+}
+
+pub fn read_partition(path: &Path, timestamp_start: i64, timestamp_end: i64) -> Result<Vec<Candle>> {
+    let mut candles = Vec::new();
+    for batch in partition_reader(path, timestamp_start, timestamp_end)? {
+        candles.extend(batch_to_candles(&batch?)?);
+    }
+    Ok(candles)
+}
+
+fn write_partition(path: &Path, candles: &[Candle]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let batch = candles_to_batch(candles)?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .build();
+    // 先写临时文件再改名, 避免中断时留下损坏的分区
+    let tmp_path = path.with_extension("parquet.tmp");
+    let mut writer = ArrowWriter::try_new(File::create(&tmp_path)?, batch.schema(), Some(props))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl ParquetStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        ParquetStore { root: root.into() }
+    }
+
+    fn partition_dir(&self, symbol: &str, interval: &str) -> PathBuf {
+        self.root.join(symbol).join(interval)
+    }
+
+    // 返回 (分区月份开始时间, 文件路径), 按时间排序
+    fn partitions(&self, symbol: &str, interval: &str) -> Vec<(i64, PathBuf)> {
+        let entries = match fs::read_dir(self.partition_dir(symbol, interval)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut partitions: Vec<(i64, PathBuf)> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|e| e == "parquet").unwrap_or(false))
+            .filter_map(|p| {
+                let month = p.file_stem()?.to_str()?;
+                let date = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d").ok()?;
+                Some((date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis(), p))
+            })
+            .collect();
+        partitions.sort_by_key(|(start, _)| *start);
+        partitions
+    }
+
+    // 按 symbol/interval/月份写入, 与已有分区合并(同一时间以新数据为准), 返回写入的条数
+    pub fn write_candles(&self, candles: &[Candle]) -> Result<usize> {
+        let mut groups: BTreeMap<PathBuf, Vec<Candle>> = BTreeMap::new();
+        for candle in candles {
+            let month = common::timestamp_millis_to_datetime(candle.timestamp).format("%Y-%m");
+            let path = self
+                .partition_dir(&candle.symbol, &candle.interval)
+                .join(format!("{}.parquet", month));
+            groups.entry(path).or_default().push(candle.clone());
+        }
+        for (path, mut group) in groups {
+            if path.exists() {
+                let timestamps: HashSet<i64> = group.iter().map(|c| c.timestamp).collect();
+                let mut merged = read_partition(&path, i64::MIN, 0)?;
+                merged.retain(|old| !timestamps.contains(&old.timestamp));
+                group.extend(merged);
+            }
+            group.sort_by_key(|c| c.timestamp);
+            group.dedup_by_key(|c| c.timestamp);
+            write_partition(&path, &group)?;
+        }
+        Ok(candles.len())
+    }
+
+    // 逐条写入 K 线流, 每凑满一个月写一次分区, 返回写入的条数
+    // 任意数据源(如 mongo)的 stream_candles 都可以直接导出到本地
+    pub async fn write_stream(&self, mut candles: CandleStream) -> std::result::Result<usize, SourceError> {
+        let mut buffer: Vec<Candle> = Vec::new();
+        let mut month = String::new();
+        let mut count = 0;
+        while let Some(candle) = candles.next().await {
+            let candle = candle?;
+            let candle_month = common::timestamp_millis_to_datetime(candle.timestamp)
+                .format("%Y-%m")
+                .to_string();
+            if candle_month != month && !buffer.is_empty() {
+                count += self.write_async(std::mem::take(&mut buffer)).await?;
+            }
+            month = candle_month;
+            buffer.push(candle);
+        }
+        if !buffer.is_empty() {
+            count += self.write_async(buffer).await?;
+        }
+        Ok(count)
+    }
+
+    async fn write_async(&self, candles: Vec<Candle>) -> std::result::Result<usize, SourceError> {
+        let store = self.clone();
+        Ok(tokio::task::spawn_blocking(move || store.write_candles(&candles)).await??)
+    }
+}
+
+impl DataSource for ParquetStore {
+    fn stream_candles(
+        &self,
+        symbol: &str,
+        interval: &str,
+        timestamp_start: i64,
+        timestamp_end: i64,
+    ) -> CandleStream {
+        let partitions = self.partitions(symbol, interval);
+        blocking_stream(move |sender| {
+            for (i, (month_start, path)) in partitions.iter().enumerate() {
+                // 分区的结束时间取下一个分区的开始时间(最后一个分区不限)
+                let month_end = partitions.get(i + 1).map(|(t, _)| *t).unwrap_or(i64::MAX);
+                if month_end <= timestamp_start || (timestamp_end > 0 && *month_start >= timestamp_end) {
+                    continue;
+                }
+                let reader = match partition_reader(path, timestamp_start, timestamp_end) {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        let _ = sender.blocking_send(Err(e.into()));
+                        return;
+                    }
+                };
+                // 每次只解码一个 record batch
+                for batch in reader {
+                    let candles = match batch.map_err(ParquetError::from).and_then(|b| batch_to_candles(&b)) {
+                        Ok(candles) => candles,
+                        Err(e) => {
+                            let _ = sender.blocking_send(Err(e.into()));
+                            return;
+                        }
+                    };
+                    for candle in candles {
+                        if sender.blocking_send(Ok(candle)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright (c) 2024 quantxyz@drg.com
+// All rights reserved.
+
+// Author: quantxyz
+// Email: lktsepc@gmail.com
+
+use super::model::{Candle, Context, Equity, Profit, TradeRecord};
+use crate::utils::common;
+
+const YEAR_MILLIS: f64 = 365.0*24.0*60.0*60.0*1000.0;
+
+// 权益曲线上的一个点
+#[derive(Debug, Clone, Copy)]
+pub struct EquityPoint {
+    pub timestamp: i64,
+    pub equity_value: f64,
+    // 是否有持仓
+    pub in_market: bool,
+}
+
+// 按时间排序(同一时间保持记录顺序), 同一时间戳只保留最后一个点
+fn dedup(curve: &[EquityPoint]) -> Vec<EquityPoint> {
+    let mut sorted = curve.to_vec();
+    sorted.sort_by_key(|p| p.timestamp);
+    let mut points: Vec<EquityPoint> = Vec::with_capacity(sorted.len());
+    for point in &sorted {
+        match points.last_mut() {
+            Some(last) if last.timestamp == point.timestamp => *last = *point,
+            _ => points.push(*point),
+        }
+    }
+    points
+}
+
+// 按权益曲线和交易记录计算绩效
+pub fn compute(item: &str, initial_capital: f64, curve: &[EquityPoint], trades: &[TradeRecord]) -> Profit {
+    let points = dedup(curve);
+    let mut profit = Profit {
+        item: item.to_string(),
+        initial_capital,
+        final_equity: points.last().map(|p| p.equity_value).unwrap_or(initial_capital),
+        ..Default::default()
+    };
+    if initial_capital > 0.0 {
+        profit.total_return = profit.final_equity/initial_capital - 1.0;
+    }
+    returns_stats(&mut profit, &points);
+    drawdown_stats(&mut profit, &points);
+    trade_stats(&mut profit, trades);
+    profit
+}
+
+// 组合的权益曲线: 组合账户模式直接用 context.portfolio,
+// 否则把各 item 的权益曲线按时间合并, 没有新点的 item 沿用上一个值(初始为 initial_capital)
+pub fn portfolio_curve(context: &Context, initial_capital: f64) -> Vec<EquityPoint> {
+    if !context.portfolio.is_empty() {
+        return context.portfolio
+            .iter()
+            .map(|p| EquityPoint{ timestamp: p.timestamp, equity_value: p.equity_value, in_market: p.exposure > 0.0 })
+            .collect();
+    }
+    let mut items: Vec<&String> = context.equities.keys().collect();
+    items.sort();
+    let mut points: Vec<(i64, usize, &Equity)> = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        points.extend(context.equities[*item].iter().map(|e| (e.timestamp, i, e)));
+    }
+    points.sort_by_key(|(timestamp, _, _)| *timestamp);
+    let mut last: Vec<(f64, bool)> = vec![(initial_capital, false); items.len()];
+    points
+        .into_iter()
+        .map(|(timestamp, i, e)| {
+            last[i] = (e.equity_value, e.pos_size != 0.0);
+            EquityPoint{
+                timestamp,
+                equity_value: last.iter().map(|(v, _)| v).sum(),
+                in_market: last.iter().any(|(_, in_market)| *in_market),
+            }
+        })
+        .collect()
+}
+
+// K 线收盘价序列, 时间为 K 线收盘时间, 用作买入持有的基准
+pub fn closes(candles: &[Candle]) -> Vec<(i64, f64)> {
+    candles
+        .iter()
+        .map(|c| (common::interval_close_time(c.timestamp, &c.interval), c.close))
+        .collect()
+}
+
+// 等权买入持有: 每个序列按首个价格归一化后取平均, 还没有数据的序列按 1 计算
+pub fn equal_weight(series: &[Vec<(i64, f64)>]) -> Vec<(i64, f64)> {
+    let mut points: Vec<(i64, usize, f64)> = Vec::new();
+    for (i, closes) in series.iter().enumerate() {
+        if let Some((_, first)) = closes.first().filter(|(_, first)| *first > 0.0) {
+            points.extend(closes.iter().map(|(t, close)| (*t, i, close/first)));
+        }
+    }
+    points.sort_by_key(|(timestamp, _, _)| *timestamp);
+    let mut last = vec![1.0; series.len()];
+    let mut basket: Vec<(i64, f64)> = Vec::new();
+    for (timestamp, i, value) in points {
+        last[i] = value;
+        let value = last.iter().sum::<f64>()/series.len() as f64;
+        match basket.last_mut() {
+            Some(point) if point.0 == timestamp => point.1 = value,
+            _ => basket.push((timestamp, value)),
+        }
+    }
+    basket
+}
+
+// 与基准对比: 把基准按权益曲线的时间对齐(取该时间之前最新的值), 用相同区间的收益率计算
+pub fn compare(profit: &mut Profit, name: &str, curve: &[EquityPoint], benchmark: &[(i64, f64)]) {
+    profit.benchmark = name.to_string();
+    let points = dedup(curve);
+    let first_value = match benchmark.first() {
+        Some((_, value)) => *value,
+        None => return,
+    };
+    let mut index = 0;
+    let aligned: Vec<(i64, f64, f64)> = points
+        .iter()
+        .map(|p| {
+            while index < benchmark.len() && benchmark[index].0 <= p.timestamp {
+                index += 1;
+            }
+            let value = if index == 0 { first_value } else { benchmark[index - 1].1 };
+            (p.timestamp, p.equity_value, value)
+        })
+        .collect();
+    let (first, last) = match (aligned.first(), aligned.last()) {
+        (Some(first), Some(last)) if last.0 > first.0 => (first, last),
+        _ => return,
+    };
+    if first.2 > 0.0 {
+        profit.benchmark_return = last.2/first.2 - 1.0;
+    }
+    let returns: Vec<(f64, f64)> = aligned
+        .windows(2)
+        .filter(|w| w[0].1 > 0.0 && w[0].2 > 0.0)
+        .map(|w| (w[1].1/w[0].1 - 1.0, w[1].2/w[0].2 - 1.0))
+        .collect();
+    if returns.len() < 2 {
+        return;
+    }
+    let periods_per_year = returns.len() as f64/((last.0 - first.0) as f64/YEAR_MILLIS);
+    let n = returns.len() as f64;
+    let mean_s = returns.iter().map(|r| r.0).sum::<f64>()/n;
+    let mean_b = returns.iter().map(|r| r.1).sum::<f64>()/n;
+    let var_s = returns.iter().map(|r| (r.0 - mean_s).powi(2)).sum::<f64>()/(n - 1.0);
+    let var_b = returns.iter().map(|r| (r.1 - mean_b).powi(2)).sum::<f64>()/(n - 1.0);
+    let cov = returns.iter().map(|r| (r.0 - mean_s)*(r.1 - mean_b)).sum::<f64>()/(n - 1.0);
+    if var_b > 0.0 {
+        profit.beta = cov/var_b;
+    }
+    if var_s > 0.0 && var_b > 0.0 {
+        profit.correlation = cov/(var_s.sqrt()*var_b.sqrt());
+    }
+    profit.alpha = (mean_s - profit.beta*mean_b)*periods_per_year;
+    let mean_excess = mean_s - mean_b;
+    let var_excess = returns.iter().map(|r| (r.0 - r.1 - mean_excess).powi(2)).sum::<f64>()/(n - 1.0);
+    profit.tracking_error = var_excess.sqrt()*periods_per_year.sqrt();
+    if profit.tracking_error > 0.0 {
+        profit.information_ratio = mean_excess*periods_per_year/profit.tracking_error;
+    }
+}
+
+fn returns_stats(profit: &mut Profit, points: &[EquityPoint]) {
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) if last.timestamp > first.timestamp => (first, last),
+        _ => return,
+    };
+    let span = (last.timestamp - first.timestamp) as f64;
+    let years = span/YEAR_MILLIS;
+    if profit.initial_capital > 0.0 && profit.final_equity > 0.0 {
+        profit.cagr = (profit.final_equity/profit.initial_capital).powf(1.0/years) - 1.0;
+    }
+    // 有持仓的时间按相邻两点之间的区间累计
+    let exposed: i64 = points
+        .windows(2)
+        .filter(|w| w[0].in_market)
+        .map(|w| w[1].timestamp - w[0].timestamp)
+        .sum();
+    profit.exposure_time = exposed as f64/span;
+
+    let returns: Vec<f64> = points
+        .windows(2)
+        .filter(|w| w[0].equity_value > 0.0)
+        .map(|w| w[1].equity_value/w[0].equity_value - 1.0)
+        .collect();
+    if returns.len() < 2 {
+        return;
+    }
+    // 按曲线的平均采样间隔年化, 对任意 K 线周期都适用
+    let periods_per_year = returns.len() as f64/years;
+    let n = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>()/n;
+    let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>()/(n - 1.0);
+    let downside = (returns.iter().map(|r| r.min(0.0).powi(2)).sum::<f64>()/n).sqrt();
+    profit.volatility = variance.sqrt()*periods_per_year.sqrt();
+    if profit.volatility > 0.0 {
+        profit.sharpe = mean*periods_per_year/profit.volatility;
+    }
+    if downside > 0.0 {
+        profit.sortino = mean*periods_per_year/(downside*periods_per_year.sqrt());
+    }
+}
+
+fn drawdown_stats(profit: &mut Profit, points: &[EquityPoint]) {
+    let mut peak = profit.initial_capital;
+    let mut peak_time = points.first().map(|p| p.timestamp).unwrap_or(0);
+    for point in points {
+        if point.equity_value >= peak {
+            peak = point.equity_value;
+            peak_time = point.timestamp;
+            continue;
+        }
+        if peak > 0.0 {
+            profit.max_drawdown = profit.max_drawdown.max(1.0 - point.equity_value/peak);
+        }
+        profit.max_drawdown_duration = profit.max_drawdown_duration.max(point.timestamp - peak_time);
+    }
+    if profit.max_drawdown > 0.0 {
+        profit.calmar = profit.cagr/profit.max_drawdown;
+    }
+}
+
+// 只统计已平仓的交易, 盈亏扣除手续费
+fn trade_stats(profit: &mut Profit, trades: &[TradeRecord]) {
+    let pnls: Vec<f64> = trades
+        .iter()
+        .filter(|t| t.time_close > 0)
+        .map(|t| t.pnl - t.fee)
+        .collect();
+    if pnls.is_empty() {
+        return;
+    }
+    let wins: Vec<f64> = pnls.iter().copied().filter(|p| *p > 0.0).collect();
+    let losses: Vec<f64> = pnls.iter().copied().filter(|p| *p <= 0.0).collect();
+    let gross_win: f64 = wins.iter().sum();
+    let gross_loss: f64 = losses.iter().sum();
+    profit.trades = pnls.len();
+    profit.win_rate = wins.len() as f64/pnls.len() as f64;
+    profit.expectancy = pnls.iter().sum::<f64>()/pnls.len() as f64;
+    if !wins.is_empty() {
+        profit.avg_win = gross_win/wins.len() as f64;
+    }
+    if !losses.is_empty() {
+        profit.avg_loss = gross_loss/losses.len() as f64;
+    }
+    profit.profit_factor = if gross_loss < 0.0 {
+        gross_win/-gross_loss
+    } else if gross_win > 0.0 {
+        f64::INFINITY
+    } else {
+        0.0
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 曲线跨度正好一年, 每 1/3 年一个点, 年化周期数为 3
+    const STEP: i64 = 365*24*60*60*1000/3;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    fn point(i: i64, equity_value: f64, in_market: bool) -> EquityPoint {
+        EquityPoint { timestamp: i*STEP, equity_value, in_market }
+    }
+
+    fn trade(pnl: f64, fee: f64, time_close: i64) -> TradeRecord {
+        TradeRecord {
+            item: "BTCUSDT_1d".to_string(),
+            side: "buy".to_string(),
+            size: 1.0,
+            price_open: 100.0,
+            time_open: 0,
+            price_close: 100.0 + pnl,
+            time_close,
+            label_close: "Close".to_string(),
+            slippage: 0.0,
+            fee,
+            pnl,
+        }
+    }
+
+    // 100 -> 120 -> 90 -> 108, 收益率 0.2, -0.25, 0.2
+    fn curve() -> Vec<EquityPoint> {
+        vec![point(0, 100.0, true), point(1, 120.0, true), point(2, 90.0, false), point(3, 108.0, false)]
+    }
+
+    #[test]
+    fn compute_hand_computed_curve() {
+        // 乱序且有重复时间戳: 排序后同一时间只保留最后记录的点
+        let points = curve();
+        let curve = vec![points[0], point(1, 115.0, true), points[1], points[3], points[2]];
+        let trades = vec![trade(10.0, 1.0, STEP), trade(-5.0, 1.0, 2*STEP), trade(20.0, 2.0, 3*STEP), trade(50.0, 1.0, 0)];
+        let profit = compute("portfolio", 100.0, &curve, &trades);
+
+        assert_close(profit.final_equity, 108.0);
+        assert_close(profit.total_return, 0.08);
+        assert_close(profit.cagr, 0.08);
+        assert_close(profit.exposure_time, 2.0/3.0);
+        // 均值 0.05, 样本方差 0.135/2, 年化波动 sqrt(0.0675*3) = 0.45
+        assert_close(profit.volatility, 0.45);
+        assert_close(profit.sharpe, 0.05*3.0/0.45);
+        // 下行偏差 sqrt(0.0625/3), 年化后为 0.25
+        assert_close(profit.sortino, 0.15/0.25);
+        // 120 回撤到 90, 到结束仍未创新高
+        assert_close(profit.max_drawdown, 0.25);
+        assert_eq!(profit.max_drawdown_duration, 2*STEP);
+        assert_close(profit.calmar, 0.08/0.25);
+
+        // 未平仓的交易不统计, 净盈亏 9, -6, 18
+        assert_eq!(profit.trades, 3);
+        assert_close(profit.win_rate, 2.0/3.0);
+        assert_close(profit.expectancy, 7.0);
+        assert_close(profit.avg_win, 13.5);
+        assert_close(profit.avg_loss, -6.0);
+        assert_close(profit.profit_factor, 27.0/6.0);
+    }
+
+    #[test]
+    fn compute_without_losses_or_points() {
+        let profit = compute("BTCUSDT_1d", 100.0, &[], &[trade(10.0, 1.0, STEP)]);
+        assert_close(profit.final_equity, 100.0);
+        assert_close(profit.total_return, 0.0);
+        assert_close(profit.sharpe, 0.0);
+        assert!(profit.profit_factor.is_infinite());
+    }
+
+    #[test]
+    fn compare_hand_computed_benchmark() {
+        // 基准收益率 0.1, -0.1, 0.1, 与策略收益率完全线性相关: s = 2.25*b - 0.025
+        // 基准的时间与曲线不一致, 按曲线时间取之前最新的值
+        let benchmark = vec![(0, 100.0), (STEP - 1, 110.0), (STEP + 5, 500.0), (2*STEP, 99.0), (3*STEP, 108.9)];
+        let mut profit = Profit::default();
+        compare(&mut profit, "BTCUSDT", &curve(), &benchmark);
+
+        assert_eq!(profit.benchmark, "BTCUSDT");
+        assert_close(profit.benchmark_return, 0.089);
+        assert_close(profit.beta, 2.25);
+        assert_close(profit.correlation, 1.0);
+        assert_close(profit.alpha, (0.05 - 2.25*0.1/3.0)*3.0);
+        // 超额收益 0.1, -0.15, 0.1
+        assert_close(profit.tracking_error, 0.25);
+        assert_close(profit.information_ratio, 0.05/3.0*3.0/0.25);
+    }
+
+    #[test]
+    fn compare_with_empty_benchmark() {
+        let mut profit = Profit::default();
+        compare(&mut profit, "none", &curve(), &[]);
+        assert_eq!(profit.benchmark, "none");
+        assert_close(profit.beta, 0.0);
+        assert_close(profit.benchmark_return, 0.0);
+    }
+}
@@ -4,13 +4,28 @@
 // Author: quantxyz
 // Email: lktsepc@gmail.com
 
-use super::broker::BrokerLocal;
-use super::model::{Candle, Equity, Order, Position, TradeRecord, StrategyParams};
-use super::model::{Context, Event};
-use tokio::sync::mpsc::{self, error::TryRecvError};
+use super::broker::{BrokerLocal, DATA_BUFFER};
+use super::model::{Candle, Equity, FillModel, Order, OrderStatus, OrderType, Position, TradeRecord, StrategyParams, StopFill, TakeProfit, TrailingStop};
+use super::model::{Context, Event, PortfolioEquity};
+use super::fee::FeeSchedule;
+use super::slippage::SlippageConfig;
+use super::stats::{self, EquityPoint};
+use super::model::Benchmark;
+use super::results::ResultsWriter;
+use super::backtest_store::BacktestStore;
+use chrono::Utc;
+use crate::utils::common;
+use super::source::{BinanceCsvSource, CachedSource, DataSource, MongoSource};
+use tokio::sync::mpsc;
 use async_trait::async_trait;
-use std::time::{Duration, Instant, SystemTime};
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::task;
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+
+// 对比基准: (名称, 价格序列)
+type BenchmarkCurve = (String, Vec<(i64, f64)>);
 
 #[async_trait]
 pub trait IStgHandler  {
@@ -35,67 +50,120 @@ pub struct Strategy {
     pub context: Context,
     pub broker: BrokerLocal,
     pub event_receiver: mpsc::UnboundedReceiver<Event>,
+    pub data_receiver: mpsc::Receiver<Event>,
+    // 每次运行的唯一标识, {stg_name}_{启动时间}
+    pub run_id: String,
+    // 由 params.tp_method 解析
+    pub take_profit: TakeProfit,
+    // params.fee_schedule, 为空时为 trading_fee 的单一费率
+    pub fee_schedule: FeeSchedule,
 }
 
 impl Strategy {
     pub fn new(params: StrategyParams) -> Self {
+        if !params.data_dir.is_empty() {
+            let source = BinanceCsvSource::new(&params.data_dir);
+            return Self::with_data_source(params, Arc::new(source));
+        }
+        let mongo = MongoSource::default();
+        if params.cache_dir.is_empty() {
+            return Self::with_data_source(params, Arc::new(mongo));
+        }
+        let cache_root = std::path::Path::new(&params.cache_dir).join(mongo.db_name());
+        let source = CachedSource::new(Arc::new(mongo), cache_root);
+        Self::with_data_source(params, Arc::new(source))
+    }
+
+    // 使用自定义数据源(文件/内存等)代替默认的 mongo
+    pub fn with_data_source(mut params: StrategyParams, data_source: Arc<dyn DataSource>) -> Self {
+        // 配置了滑点文件却读不到时不能按无滑点回测, 直接失败
+        if !params.slippage_file.is_empty() {
+            params.slippage = SlippageConfig::from_file(&params.slippage_file)
+                .unwrap_or_else(|e| panic!("load slippage {} error: {:?}", params.slippage_file, e));
+        }
+        // 手续费文件同理, 读不到时不能退回 trading_fee
+        if !params.fee_file.is_empty() {
+            let fee_schedule = FeeSchedule::from_file(&params.fee_file)
+                .unwrap_or_else(|e| panic!("load fee schedule {} error: {:?}", params.fee_file, e));
+            params.fee_schedule = Some(fee_schedule);
+        }
         let (sender, receiver) = mpsc::unbounded_channel();
-        let broker = BrokerLocal::new(sender);
+        let (data_sender, data_receiver) = mpsc::channel(DATA_BUFFER);
+        let broker = BrokerLocal::with_data_source(sender, data_sender, data_source);
         let context = Context::new();
+        // tp_method 为空时按 n_atr_tp 倍 ATR 止盈
+        let take_profit = if params.tp_method.is_empty() {
+            TakeProfit::Atr(params.n_atr_tp)
+        } else {
+            params.tp_method.parse().unwrap_or_else(|e| {
+                log::error!("{}, use atr_{}", e, params.n_atr_tp);
+                TakeProfit::Atr(params.n_atr_tp)
+            })
+        };
+
+        let fee_schedule = params
+            .fee_schedule
+            .clone()
+            .unwrap_or_else(|| FeeSchedule::flat(params.trading_fee));
+
+        let run_id = format!("{}_{}", params.stg_name, Utc::now().format("%Y%m%d_%H%M%S"));
 
         Strategy {
+            run_id,
+            take_profit,
+            fee_schedule,
             params,
             context,
             broker,
             event_receiver: receiver,
+            data_receiver,
         }
     }
 
     // 提取事件处理逻辑到一个单独的异步函数
     async fn handle_events(&mut self) {
-        let mut start_time = Instant::now();
         loop {
-            match self.event_receiver.try_recv() {
-                Ok(event) => {
-                    start_time = Instant::now();
-                    match event {
-                        Event::EventFinish() => {
-                            self.on_finish().await;
-                            break;
-                        }
-                        Event::EventCandle(candle) => {
-                            self.context.push_candle(candle.clone());
-                            self.on_candle(&candle).await;
-                        },
-                        Event::EventPosition(position) => {
-                            self.on_position(&position).await;
-                        },
-                        Event::EventOrder(order) => {
-                            self.on_order(&order).await;
-                        },
-                        Event::EventEquity(equity) => {
-                            self.context.push_equity(equity.clone());
-                            self.on_equity(&equity).await;
-                        },
-                        Event::EventTradeRecord(trade_record) => {
-                            self.context.push_trade_record(trade_record.clone());
-                            self.on_trade_record(&trade_record).await;
-                        },
+            // 先处理策略自身产生的事件, 没有再等待下一根 K 线
+            let event = match self.event_receiver.try_recv() {
+                Ok(event) => event,
+                Err(_) => match self.data_receiver.recv().await {
+                    Some(event) => event,
+                    None => {
+                        log::info!("Receiver has closed and no more events will be received.");
+                        Event::EventFinish()
                     }
-                }
-                Err(TryRecvError::Empty) => {
-                    // No messages available right now, await for new messages
-                    let current_time = Instant::now();
-                    let duration = current_time.duration_since(start_time);
-                    // 判断时间间隔是否超过一分钟
-                    if duration > Duration::from_secs(20) {
-                        let _ = self.broker.event_sender.send(Event::EventFinish());
-                    }
-                }
-                Err(TryRecvError::Disconnected) => {
-                    log::info!("Receiver has closed and no more events will be received.");
+                },
+            };
+            match event {
+                // broker 读完所有数据源后发送
+                Event::EventFinish() => {
+                    self.compute_profits().await;
+                    self.write_results();
+                    self.save_results().await;
+                    self.on_finish().await;
                     break;
                 }
+                Event::EventCandle(candle) => {
+                    self.context.push_candle(candle.clone());
+                    self.match_orders(&candle).await;
+                    self.check_stops(&candle).await;
+                    self.update_trailing_stop(&candle);
+                    self.mark_to_market(&candle);
+                    self.on_candle(&candle).await;
+                },
+                Event::EventPosition(position) => {
+                    self.on_position(&position).await;
+                },
+                Event::EventOrder(order) => {
+                    self.on_order(&order).await;
+                },
+                // 持仓/资金/交易记录已在成交时写入 context, 这里只回调
+                Event::EventEquity(equity) => {
+                    self.on_equity(&equity).await;
+                },
+                Event::EventTradeRecord(trade_record) => {
+                    self.on_trade_record(&trade_record).await;
+                },
             }
         }
     }
@@ -105,7 +173,9 @@ impl Strategy {
     ) {
         let symbols = self.params.symbols.clone();
         let intervals = self.params.intervals.clone();
-        let broker = self.broker.clone();
+        // 唯一的 data_sender 移到 broker 任务中, 任务异常退出时 data_receiver 返回 None, 回测随之结束
+        let mut broker = self.broker.clone();
+        broker.data_sender = self.broker.data_sender.take();
         let _items_timestamp_start = self.params.items_timestamp_start.clone();
         let _items_timestamp_end = self.params.items_timestamp_end.clone();
         self.init().await;
@@ -127,14 +197,10 @@ impl Strategy {
         for symbol in symbols {
             for interval in &intervals {
                 let item = format!("{}_{}", symbol, interval);
-                self.context.push_equity(Equity{
-                    item: item.clone(),
-                    timestamp,
-                    equity_value: self.params.initial_capital,
-                    close_latest: 0.0,
-                    pos_size: 0.0,
-                    cash_aval: self.params.initial_capital,
-                });
+                // 权益曲线从回测开始时间算起
+                let timestamp_start = self.params.items_timestamp_start.get(&item).copied().unwrap_or(timestamp);
+                let equity = self.last_equity(&item, timestamp_start);
+                self.context.push_equity(equity);
                 self.context.update_position(Position{
                     item: item.clone(),
                     size: 0.0,
@@ -142,36 +208,120 @@ impl Strategy {
                     highest: 0.0,
                     lowest: 0.0,
                     stop_loss: 0.0,
+                    trailing_stop: 0.0,
                     take_profit: 0.0,
                     timestamp,
                 });
             }
         }
+        if self.params.is_portfolio {
+            let timestamp_start = self.params.items_timestamp_start.values().min().copied().unwrap_or(timestamp);
+            self.update_portfolio(timestamp_start);
+        }
         self.on_init().await;
     }
-    async fn process_order(&mut self, order: &Order) {
-        let item = order.item.clone();
-        let qty = order.qty;
-        let price = order.price;
-        let timestamp = order.timestamp;
-        if let Some(last_pos) = self.context.get_position(&item) {
-            if let Some(value) = self.context.get_atr(&item) {
-                let pos_avg_price = (last_pos.size*last_pos.price+qty*price)/(last_pos.size+qty);
-                let position = Position{
+    // 根据 ATR 计算持仓的止损价, 未开启或没有 ATR 时为 0
+    fn stop_loss_price(&self, size: f64, price: f64, atr: Option<f64>) -> f64 {
+        match atr {
+            Some(atr) if size != 0.0 && self.params.is_sl => {
+                (price - size.signum()*self.params.n_atr_sl*atr).max(0.0)
+            }
+            _ => 0.0,
+        }
+    }
+    // 按止盈方式计算止盈价, stage 为分批止盈已完成的档数, 没有下一档时为 0
+    fn take_profit_price(&self, size: f64, price: f64, stop_loss: f64, atr: Option<f64>, stage: usize) -> f64 {
+        if size == 0.0 || !self.params.is_tp {
+            return 0.0;
+        }
+        let side = size.signum();
+        let distance = match &self.take_profit {
+            TakeProfit::Percent(percent) => Some(price*percent),
+            TakeProfit::Atr(n) => atr.map(|atr| n*atr),
+            TakeProfit::RiskReward(ratio) if stop_loss > 0.0 => Some((price - stop_loss).abs()*ratio),
+            TakeProfit::RiskReward(_) => None,
+            TakeProfit::Ladder(steps) => steps.get(stage).map(|(percent, _)| price*percent),
+        };
+        distance.map(|d| (price + side*d).max(0.0)).unwrap_or(0.0)
+    }
+    // 持仓账本: 按成交更新持仓/交易记录/资金, 反向成交先平仓(可部分), 剩余部分反手开仓
+    // label_close: 平仓时记录到 TradeRecord 的原因, 如 Close/StopLoss/TakeProfit
+    async fn process_order(&mut self, item: &str, fill: &Fill, label_close: &str) {
+        let item = item.to_string();
+        let qty = fill.qty;
+        let price = fill.price;
+        let timestamp = fill.timestamp;
+        let atr = self.context.get_atr(&item);
+        let last_size = self.context.get_position(&item).map(|p| p.size).unwrap_or(0.0);
+        let size = last_size + qty;
+        // 全部平仓
+        let is_closed = size.abs() <= f64::EPSILON*last_size.abs().max(1.0);
+        // 减仓
+        let is_reduced = !is_closed && last_size*qty < 0.0 && last_size*size > 0.0;
+        // 平掉的数量(与原持仓同号)和新开仓的数量
+        let closed_qty = if last_size*qty < 0.0 { last_size.signum()*qty.abs().min(last_size.abs()) } else { 0.0 };
+        let opening_qty = qty + closed_qty;
+        let last_price = self.context.get_position(&item).map(|p| p.price).unwrap_or(0.0);
+        let pnl = (price - last_price)*closed_qty;
+        if let Some(last_pos) = self.context.get_position(&item).cloned() {
+            let position = if is_closed {
+                Position{
                     item: item.to_string(),
-                    size: if last_pos.size*qty > 0.0 { last_pos.size+qty } else { qty },
-                    price: if last_pos.size*qty > 0.0 { pos_avg_price } else { price },
-                    highest: if last_pos.highest > 0.0 { last_pos.highest } else { price },
-                    lowest: if last_pos.lowest > 0.0 { last_pos.lowest } else { price },
-                    stop_loss: pos_avg_price - self.params.n_atr_sl*value,
-                    take_profit: pos_avg_price + self.params.n_atr_tp*value,
+                    size: 0.0,
+                    price: 0.0,
+                    highest: 0.0,
+                    lowest: 0.0,
+                    stop_loss: 0.0,
+                    trailing_stop: 0.0,
+                    take_profit: 0.0,
                     timestamp,
+                }
+            } else if is_reduced {
+                // 减仓不改变开仓均价和止损止盈
+                Position{
+                    size,
+                    timestamp,
+                    ..last_pos
+                }
+            } else {
+                let is_new = last_pos.size*size <= 0.0;
+                let avg_price = if is_new { price } else { (last_pos.size*last_pos.price+qty*price)/size };
+                let highest = if is_new { price } else { last_pos.highest };
+                let lowest = if is_new { price } else { last_pos.lowest };
+                let trailing_stop = if is_new {
+                    self.trailing_stop_price(size, highest, lowest, atr)
+                } else {
+                    last_pos.trailing_stop
                 };
-                self.context.update_position(position.clone());
-                let _ = self.broker.event_sender.send(Event::EventPosition(position));
-            }
-        }        
-        if let Some(last_trade_record) = self.context.get_last_trade_record(&item) {
+                if is_new {
+                    self.context.tp_ladders.insert(item.clone(), (0, size.abs()));
+                }
+                let stage = self.context.tp_ladders.get(&item).map(|(stage, _)| *stage).unwrap_or(0);
+                let stop_loss = self.stop_loss_price(size, avg_price, atr);
+                Position{
+                    item: item.to_string(),
+                    size,
+                    price: avg_price,
+                    highest,
+                    lowest,
+                    stop_loss,
+                    trailing_stop,
+                    take_profit: self.take_profit_price(size, avg_price, stop_loss, atr, stage),
+                    timestamp,
+                }
+            };
+            self.context.update_position(position.clone());
+            let _ = self.broker.event_sender.send(Event::EventPosition(position));
+        }
+        self.context.volume_30d.push(timestamp, (qty*price).abs());
+        *self.context.fees.entry(item.clone()).or_default() += fill.fee;
+        // 只有未平仓的记录才能加仓或平仓
+        let open_record = self
+            .context
+            .get_last_trade_record(&item)
+            .filter(|tr| tr.time_close == 0)
+            .cloned();
+        if let Some(last_trade_record) = open_record {
             if last_trade_record.size*qty > 0.0 {
                 let size = last_trade_record.size + qty;
                 let price_open = (last_trade_record.price_open*last_trade_record.size + price*qty)/size;
@@ -180,40 +330,70 @@ impl Strategy {
                     side: last_trade_record.side.to_string(),
                     size,
                     price_open,
-                    time_open: timestamp,
+                    time_open: last_trade_record.time_open,
                     price_close: 0.0,
                     time_close: 0,
                     label_close: "".to_string(),
+                    slippage: last_trade_record.slippage + fill.slippage,
+                    fee: last_trade_record.fee + fill.fee,
+                    pnl: 0.0,
                 };
-                self.context.update_trade_record(tr);
+                self.context.update_trade_record(tr.clone());
+                let _ = self.broker.event_sender.send(Event::EventTradeRecord(tr));
             } else {
-                // close sell or buy
+                // close sell or buy, 减仓时只平掉对应的部分
+                let closed_size = if is_reduced { -qty } else { last_trade_record.size };
+                // 滑点和手续费按数量分摊到平掉的部分和剩余/反手的部分
+                let open_share = closed_size/last_trade_record.size;
+                let close_share = (closed_size/qty).abs();
+                let open_slippage = last_trade_record.slippage*open_share;
+                let close_slippage = fill.slippage*close_share;
+                let open_fee = last_trade_record.fee*open_share;
+                let close_fee = fill.fee*close_share;
                 let tr_close = TradeRecord{
                     item: item.to_string(),
                     side: last_trade_record.side.clone(),
-                    size: last_trade_record.size,
+                    size: closed_size,
                     price_open: last_trade_record.price_open,
                     time_open: last_trade_record.time_open,
                     price_close: price,
                     time_close: timestamp,
-                    label_close: "Close".to_string(),
+                    label_close: label_close.to_string(),
+                    slippage: open_slippage + close_slippage,
+                    fee: open_fee + close_fee,
+                    pnl,
                 };
                 // update trade record
-                self.context.update_trade_record(tr_close);
-                // make a new trade record
-                let tr = TradeRecord{
-                    item: item.to_string(),
-                    side: if qty > 0.0 {"buy".to_string()} else {"sell".to_string()},
-                    size: qty,
-                    price_open: price,
-                    time_open: timestamp,
-                    price_close: 0.0,
-                    time_close: 0,
-                    label_close: "".to_string(),
-                };
-                // push the new trade record
-                
-                let _ = self.broker.event_sender.send(Event::EventTradeRecord(tr));
+                self.context.update_trade_record(tr_close.clone());
+                let _ = self.broker.event_sender.send(Event::EventTradeRecord(tr_close));
+                // 减仓剩余部分或反手时 make a new trade record
+                if !is_closed {
+                    let tr = if is_reduced {
+                        TradeRecord{
+                            size: last_trade_record.size + qty,
+                            slippage: last_trade_record.slippage - open_slippage,
+                            fee: last_trade_record.fee - open_fee,
+                            ..last_trade_record
+                        }
+                    } else {
+                        TradeRecord{
+                            item: item.to_string(),
+                            side: if qty > 0.0 {"buy".to_string()} else {"sell".to_string()},
+                            size,
+                            price_open: price,
+                            time_open: timestamp,
+                            price_close: 0.0,
+                            time_close: 0,
+                            label_close: "".to_string(),
+                            slippage: fill.slippage - close_slippage,
+                            fee: fill.fee - close_fee,
+                            pnl: 0.0,
+                        }
+                    };
+                    // push the new trade record
+                    self.context.push_trade_record(tr.clone());
+                    let _ = self.broker.event_sender.send(Event::EventTradeRecord(tr));
+                }
             }
             
         } else {
@@ -226,68 +406,354 @@ impl Strategy {
                 price_close: 0.0,
                 time_close: 0,
                 label_close: "".to_string(),
+                slippage: fill.slippage,
+                fee: fill.fee,
+                pnl: 0.0,
             };
+            self.context.push_trade_record(tr.clone());
             let _ = self.broker.event_sender.send(Event::EventTradeRecord(tr));
         }
-        let _ = self.broker.event_sender.send(Event::EventOrder(order.clone()));
+        // 平仓退回保证金并结算盈亏, 开仓占用保证金, 都扣手续费
+        let last_equity = self.last_equity(&item, timestamp);
+        let cash_aval = last_equity.cash_aval + closed_qty.abs()*last_price + pnl - opening_qty.abs()*price - fill.fee;
+        let realized_pnl = last_equity.realized_pnl + pnl - fill.fee;
+        self.push_equity(&item, price, timestamp, cash_aval, realized_pnl);
     }
-    pub async fn buy(&mut self, item: &String, price: f64, timestamp: i64, qty: Option<f64>) {
-        // 判断资金是否够(下单金额和手续费)
-        // 够则下单
-        // 然后推送on_order
-        // 然后推送on_equity
-        // 然后推送on_on_position
-        // 然后推送on_trade_record
-        let qty_value = match qty {
-            Some(size) => size,
-            None => 0.0,
-        };
-        let mut margin = if self.params.is_use_percent_of_equity {
-            self.params.initial_capital*self.params.percent_of_equity
-        } else {
-            self.params.initial_capital*self.params.percent_of_every_trade_money
+    // 按 price 重新估值 item 的持仓, 记录一条 Equity
+    fn push_equity(&mut self, item: &str, price: f64, timestamp: i64, cash_aval: f64, realized_pnl: f64) {
+        let (size, avg_price) = self.context.get_position(item).map(|p| (p.size, p.price)).unwrap_or((0.0, 0.0));
+        let unrealized_pnl = (price - avg_price)*size;
+        let equity = Equity{
+            item: item.to_string(),
+            timestamp,
+            equity_value: cash_aval + size.abs()*avg_price + unrealized_pnl,
+            close_latest: price,
+            pos_size: size,
+            cash_aval,
+            unrealized_pnl,
+            realized_pnl,
+            exposure: size.abs()*price,
         };
-        if 0.0 < qty_value && qty_value < margin {
-            margin = qty_value;
+        self.context.push_equity(equity.clone());
+        let _ = self.broker.event_sender.send(Event::EventEquity(equity));
+        if self.params.is_portfolio {
+            self.update_portfolio(timestamp);
         }
-        let mut last_equity = Equity{
-            item: item.clone(),
+    }
+    // K 线走完后按收盘价重新估值持仓
+    fn mark_to_market(&mut self, candle: &Candle) {
+        let item = format!("{}_{}", candle.symbol, candle.interval);
+        let timestamp = common::interval_close_time(candle.timestamp, &candle.interval);
+        let last_equity = self.last_equity(&item, timestamp);
+        self.push_equity(&item, candle.close, timestamp, last_equity.cash_aval, last_equity.realized_pnl);
+    }
+    // item 最新的资金, 组合账户时 item 从 0 开始
+    fn last_equity(&mut self, item: &str, timestamp: i64) -> Equity {
+        let capital = if self.params.is_portfolio { 0.0 } else { self.params.initial_capital };
+        self.context.get_last_equity(item).cloned().unwrap_or(Equity{
+            item: item.to_string(),
             timestamp,
-            equity_value: self.params.initial_capital,
+            equity_value: capital,
             close_latest: 0.0,
             pos_size: 0.0,
+            cash_aval: capital,
+            unrealized_pnl: 0.0,
+            realized_pnl: 0.0,
+            exposure: 0.0,
+        })
+    }
+    // 组合账户时为所有 item 汇总的资金, 否则为 item 自己的
+    fn account_equity(&mut self, item: &str, timestamp: i64) -> Equity {
+        if !self.params.is_portfolio {
+            return self.last_equity(item, timestamp);
+        }
+        let portfolio = self.portfolio_equity(timestamp);
+        Equity{
+            timestamp,
+            equity_value: portfolio.equity_value,
+            cash_aval: portfolio.cash_aval,
+            ..self.last_equity(item, timestamp)
+        }
+    }
+    fn portfolio_equity(&self, timestamp: i64) -> PortfolioEquity {
+        let mut portfolio = PortfolioEquity{
+            timestamp,
+            equity_value: self.params.initial_capital,
             cash_aval: self.params.initial_capital,
+            margin_used: 0.0,
+            unrealized_pnl: 0.0,
+            realized_pnl: 0.0,
+            exposure: 0.0,
         };
-        if let Some(last_one) = self.context.get_last_equity(&item) {
-            last_equity = last_one.clone();
+        for equity in self.context.equities.values().filter_map(|v| v.last()) {
+            portfolio.equity_value += equity.equity_value;
+            portfolio.cash_aval += equity.cash_aval;
+            portfolio.unrealized_pnl += equity.unrealized_pnl;
+            portfolio.realized_pnl += equity.realized_pnl;
+            portfolio.exposure += equity.exposure;
         }
-        if margin*(1.00+self.params.trading_fee) < last_equity.cash_aval {
-            let qty = margin/price;
-            let order = Order{
-                item: item.to_string(),
-                price,
-                qty,
-                timestamp,
+        for position in self.context.positions.values() {
+            portfolio.margin_used += position.size.abs()*position.price;
+        }
+        portfolio
+    }
+    fn update_portfolio(&mut self, timestamp: i64) {
+        let portfolio = self.portfolio_equity(timestamp);
+        self.context.portfolio.push(portfolio);
+    }
+    // 回测结束时计算每个 item 和整个组合的绩效, 写入 context.profits
+    // 组合账户模式下 item 的权益从 0 开始, 按占 initial_capital 的收益计算
+    async fn compute_profits(&mut self) {
+        let capital = self.params.initial_capital;
+        let base = if self.params.is_portfolio { capital } else { 0.0 };
+        let mut items: Vec<String> = self.context.equities.keys().cloned().collect();
+        items.sort();
+        let (item_benchmarks, portfolio_benchmark) = self.benchmark_curves(&items).await;
+        let mut profits = Vec::new();
+        let mut all_trades = Vec::new();
+        for item in &items {
+            let curve: Vec<EquityPoint> = self.context.equities[item]
+                .iter()
+                .map(|e| EquityPoint{ timestamp: e.timestamp, equity_value: base + e.equity_value, in_market: e.pos_size != 0.0 })
+                .collect();
+            let trades = self.context.trade_records.get(item).cloned().unwrap_or_default();
+            let mut profit = stats::compute(item, capital, &curve, &trades);
+            if let Some((name, benchmark)) = item_benchmarks.get(item) {
+                stats::compare(&mut profit, name, &curve, benchmark);
+            }
+            profits.push(profit);
+            all_trades.extend(trades);
+        }
+        let initial = if self.params.is_portfolio { capital } else { capital*items.len() as f64 };
+        let curve = stats::portfolio_curve(&self.context, capital);
+        all_trades.sort_by_key(|t| t.time_close);
+        let mut profit = stats::compute("portfolio", initial, &curve, &all_trades);
+        if let Some((name, benchmark)) = &portfolio_benchmark {
+            stats::compare(&mut profit, name, &curve, benchmark);
+        }
+        profits.push(profit);
+        for profit in profits {
+            self.context.push_profit(profit);
+        }
+    }
+    // 每个 item 和组合对比的基准: (基准名称, 价格序列)
+    async fn benchmark_curves(&self, items: &[String]) -> (HashMap<String, BenchmarkCurve>, Option<BenchmarkCurve>) {
+        let mut curves = HashMap::new();
+        let portfolio = match &self.params.benchmark {
+            Benchmark::None => None,
+            Benchmark::BuyAndHold | Benchmark::EqualWeight => {
+                let series: Vec<Vec<(i64, f64)>> = items
+                    .iter()
+                    .map(|item| stats::closes(self.context.candles.get(item).map(|c| c.as_slice()).unwrap_or(&[])))
+                    .collect();
+                let basket = ("equal_weight".to_string(), stats::equal_weight(&series));
+                for (item, closes) in items.iter().zip(series) {
+                    let curve = if self.params.benchmark == Benchmark::BuyAndHold {
+                        (item.clone(), closes)
+                    } else {
+                        basket.clone()
+                    };
+                    curves.insert(item.clone(), curve);
+                }
+                Some(basket)
+            }
+            Benchmark::Symbol(symbol) => {
+                // 每个周期读取一次基准 K 线, 组合使用第一个周期
+                let mut by_interval: HashMap<String, BenchmarkCurve> = HashMap::new();
+                for interval in &self.params.intervals {
+                    let closes = self.load_closes(symbol, interval).await;
+                    by_interval.insert(interval.clone(), (format!("{}_{}", symbol, interval), closes));
+                }
+                for item in items {
+                    let interval = item.rsplit_once('_').map(|(_, interval)| interval).unwrap_or_default();
+                    if let Some(curve) = by_interval.get(interval) {
+                        curves.insert(item.clone(), curve.clone());
+                    }
+                }
+                self.params.intervals.first().and_then(|interval| by_interval.remove(interval))
+            }
+        };
+        (curves, portfolio)
+    }
+    // 从数据源读取 symbol 在整个回测时间窗口内的收盘价
+    async fn load_closes(&self, symbol: &str, interval: &str) -> Vec<(i64, f64)> {
+        let timestamp_start = self.params.items_timestamp_start.values().min().copied().unwrap_or(0);
+        let timestamp_end = self.params.items_timestamp_end.values().max().copied().unwrap_or(0);
+        let mut stream = self.broker.data_source.stream_candles(symbol, interval, timestamp_start, timestamp_end);
+        let mut candles = Vec::new();
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(candle) => candles.push(candle),
+                Err(e) => {
+                    log::error!("load benchmark {}_{} failed: {}", symbol, interval, e);
+                    break;
+                }
+            }
+        }
+        stats::closes(&candles)
+    }
+    fn write_results(&self) {
+        if self.params.results_dir.is_empty() {
+            return;
+        }
+        let writer = ResultsWriter::new(&self.params.results_dir, &self.run_id);
+        let result = writer
+            .write(&self.context)
+            .and_then(|_| writer.write_report(&self.run_id, &self.context, self.params.initial_capital));
+        match result {
+            Ok(()) => log::info!("results saved to {}", writer.dir().display()),
+            Err(e) => log::error!("write results to {} failed: {}", writer.dir().display(), e),
+        }
+    }
+    async fn save_results(&self) {
+        if self.params.results_db.is_empty() {
+            return;
+        }
+        let store = BacktestStore::with_db_name(self.params.results_db.clone());
+        match store.save(&self.run_id, get_timestamp_ms(), &self.params, &self.context).await {
+            Ok(()) => log::info!("results saved to mongo {}, run_id: {}", store.db_name(), self.run_id),
+            Err(e) => log::error!("save results to mongo {} failed: {}", store.db_name(), e),
+        }
+    }
+    // 订单状态变化时记录到 order_history 并回调 on_order
+    fn emit_order(&mut self, order: Order) {
+        self.context.order_history.push(order.clone());
+        let _ = self.broker.event_sender.send(Event::EventOrder(order));
+    }
+    // 按 price 平掉 item 的 size 数量(不超过持仓)的仓位
+    // order_type: 止损为 Stop, 止盈为 Limit
+    async fn reduce_at(&mut self, item: &str, size: f64, price: f64, timestamp: i64, order_type: OrderType, label_close: &str) {
+        let position = match self.context.get_position(item) {
+            Some(position) if position.size != 0.0 => position.clone(),
+            _ => return,
+        };
+        let closed = if size >= position.size.abs() { position.size } else { size*position.size.signum() };
+        let order = Order{
+            id: self.context.next_order_id(),
+            order_type,
+            ..Order::market(item, -closed, price, timestamp)
+        };
+        let fill = self.make_fill(&order, -closed, price, timestamp);
+        self.process_order(item, &fill, label_close).await;
+        self.emit_order(apply_fill(order, &fill));
+    }
+    // 按 price 止损平掉 item 的全部持仓
+    async fn close_at(&mut self, item: &str, price: f64, timestamp: i64, label_close: &str) {
+        self.reduce_at(item, f64::INFINITY, price, timestamp, OrderType::Stop, label_close).await;
+    }
+    // 跟踪止损价: 多头为最高价下方, 空头为最低价上方, 未开启时为 0
+    fn trailing_stop_price(&self, size: f64, highest: f64, lowest: f64, atr: Option<f64>) -> f64 {
+        let distance = |extreme: f64| match self.params.trailing_stop {
+            TrailingStop::None => None,
+            TrailingStop::Atr(n) => atr.map(|atr| n*atr),
+            TrailingStop::Percent(percent) => Some(extreme*percent),
+        };
+        if size > 0.0 {
+            distance(highest).map(|d| (highest - d).max(0.0)).unwrap_or(0.0)
+        } else if size < 0.0 {
+            distance(lowest).map(|d| lowest + d).unwrap_or(0.0)
+        } else {
+            0.0
+        }
+    }
+    // 用新 K 线的最高/最低价检查持仓的止损和止盈, 触发则按止损/止盈价平仓
+    async fn check_stops(&mut self, candle: &Candle) {
+        let item = format!("{}_{}", candle.symbol, candle.interval);
+        let position = match self.context.get_position(&item) {
+            Some(position) if position.size != 0.0 => position.clone(),
+            _ => return,
+        };
+        let is_long = position.size > 0.0;
+        let gap_open = self.params.stop_fill == StopFill::GapOpen;
+        // 固定止损和跟踪止损取离当前价更近的一个
+        let mut stops = vec![];
+        if self.params.is_sl && position.stop_loss > 0.0 {
+            stops.push((position.stop_loss, "StopLoss"));
+        }
+        if position.trailing_stop > 0.0 {
+            stops.push((position.trailing_stop, "TrailingStop"));
+        }
+        let stop = if is_long {
+            stops.into_iter().max_by(|a, b| a.0.total_cmp(&b.0))
+        } else {
+            stops.into_iter().min_by(|a, b| a.0.total_cmp(&b.0))
+        };
+        // 同一根 K 线同时触及止损和止盈时无法判断先后, 保守地按止损处理
+        if let Some((stop_price, label)) = stop {
+            let price = if is_long && candle.low <= stop_price {
+                Some(if gap_open { candle.open.min(stop_price) } else { stop_price })
+            } else if !is_long && candle.high >= stop_price {
+                Some(if gap_open { candle.open.max(stop_price) } else { stop_price })
+            } else {
+                None
             };
-            self.process_order(&order).await;
-            let equity = Equity{
-                item: item.to_string(),
-                timestamp,
-                equity_value: last_equity.equity_value-margin*self.params.trading_fee,
-                close_latest: price,
-                pos_size: if last_equity.pos_size > 0.0 { last_equity.pos_size+qty } else { qty },
-                cash_aval: last_equity.cash_aval-margin*(1.00+self.params.trading_fee),
+            if let Some(price) = price {
+                self.close_at(&item, price, candle.timestamp, label).await;
+                return;
+            }
+        }
+        let take_profit = position.take_profit;
+        if self.params.is_tp && take_profit > 0.0 {
+            let price = if is_long && candle.high >= take_profit {
+                Some(if gap_open { candle.open.max(take_profit) } else { take_profit })
+            } else if !is_long && candle.low <= take_profit {
+                Some(if gap_open { candle.open.min(take_profit) } else { take_profit })
+            } else {
+                None
             };
-            let _ = self.broker.event_sender.send(Event::EventEquity(equity));
+            if let Some(price) = price {
+                self.take_profit_at(&item, price, candle.timestamp).await;
+            }
         }
-        
     }
-    pub async fn sell(&mut self, item: &String, price: f64, timestamp: i64, qty: Option<f64>) {
-        let qty_value = match qty {
-            Some(size) => size,
-            None => 0.0,
+    // 止盈: 分批止盈时按档位平掉初始仓位的对应比例并把止盈价移到下一档, 否则全部平仓
+    async fn take_profit_at(&mut self, item: &str, price: f64, timestamp: i64) {
+        let steps = match &self.take_profit {
+            TakeProfit::Ladder(steps) => steps.clone(),
+            _ => return self.reduce_at(item, f64::INFINITY, price, timestamp, OrderType::Limit, "TakeProfit").await,
         };
-
+        let (stage, initial_size) = self.context.tp_ladders.get(item).cloned().unwrap_or((0, 0.0));
+        let fraction = steps.get(stage).map(|(_, fraction)| *fraction).unwrap_or(1.0);
+        // 最后一档平掉剩余全部仓位
+        let size = if stage + 1 >= steps.len() { f64::INFINITY } else { initial_size*fraction };
+        self.reduce_at(item, size, price, timestamp, OrderType::Limit, "TakeProfit").await;
+        self.context.tp_ladders.insert(item.to_string(), (stage + 1, initial_size));
+        if let Some(position) = self.context.get_position(item).cloned() {
+            if position.size != 0.0 {
+                let atr = self.context.get_atr(item);
+                let take_profit = self.take_profit_price(position.size, position.price, position.stop_loss, atr, stage + 1);
+                self.context.update_position(Position{
+                    take_profit,
+                    ..position
+                });
+            }
+        }
+    }
+    // K 线走完后更新持仓期间的最高/最低价, 跟踪止损只朝有利方向移动
+    fn update_trailing_stop(&mut self, candle: &Candle) {
+        let item = format!("{}_{}", candle.symbol, candle.interval);
+        let mut position = match self.context.get_position(&item) {
+            Some(position) if position.size != 0.0 => position.clone(),
+            _ => return,
+        };
+        position.highest = position.highest.max(candle.high);
+        position.lowest = position.lowest.min(candle.low);
+        let atr = self.context.get_atr(&item);
+        let trailing_stop = self.trailing_stop_price(position.size, position.highest, position.lowest, atr);
+        if trailing_stop > 0.0 {
+            position.trailing_stop = if position.trailing_stop <= 0.0 {
+                trailing_stop
+            } else if position.size > 0.0 {
+                position.trailing_stop.max(trailing_stop)
+            } else {
+                position.trailing_stop.min(trailing_stop)
+            };
+        }
+        self.context.update_position(position);
+    }
+    // 每笔下单金额, qty 为金额上限
+    pub fn order_margin(&self, qty: Option<f64>) -> f64 {
+        let qty_value = qty.unwrap_or(0.0);
         let mut margin = if self.params.is_use_percent_of_equity {
             self.params.initial_capital*self.params.percent_of_equity
         } else {
@@ -296,37 +762,747 @@ impl Strategy {
         if 0.0 < qty_value && qty_value < margin {
             margin = qty_value;
         }
-        let mut last_equity = Equity{
-            item: item.clone(),
-            timestamp,
-            equity_value: self.params.initial_capital,
-            close_latest: 0.0,
-            pos_size: 0.0,
-            cash_aval: self.params.initial_capital,
+        margin
+    }
+    // 成交订单的一部分或全部, 新开仓部分占用资金, 平仓部分先退回资金, 资金不足时拒绝, 返回成交后的订单
+    async fn fill_order(&mut self, order: Order, fill: Fill) -> Option<Order> {
+        let item = order.item.clone();
+        let opening_qty = self.opening_qty(&item, fill.qty);
+        let margin = opening_qty.abs()*fill.price;
+        let released = match self.context.get_position(&item) {
+            Some(position) => {
+                let closed_qty = fill.qty - opening_qty;
+                closed_qty.abs()*position.price - (fill.price - position.price)*closed_qty
+            }
+            None => 0.0,
         };
-        if let Some(last_one) = self.context.get_last_equity(&item) {
-            last_equity = last_one.clone();
+        let cash_aval = self.account_equity(&item, fill.timestamp).cash_aval + released;
+        if margin > 0.0 && margin + fill.fee >= cash_aval {
+            log::warn!("{} cash not enough, order rejected: {:?}", item, order);
+            self.reject_order(order);
+            return None;
         }
-        if margin*(1.00+self.params.trading_fee) < last_equity.cash_aval {
-            let qty = -margin/price;
-            let order = Order{
-                item: item.to_string(),
-                price,
-                qty,
-                timestamp,
+        self.process_order(&item, &fill, "Close").await;
+        let order = apply_fill(order, &fill);
+        self.emit_order(order.clone());
+        Some(order)
+    }
+    pub async fn buy(&mut self, item: &str, price: f64, timestamp: i64, qty: Option<f64>) {
+        // 判断资金是否够(下单金额和手续费)
+        // 够则下单
+        // 然后推送on_order
+        // 然后推送on_equity
+        // 然后推送on_on_position
+        // 然后推送on_trade_record
+        // 持有空头时先减空头, 超过的部分开多
+        let margin = self.order_margin(qty);
+        self.place_order(Order::market(item, margin/price, price, timestamp)).await;
+    }
+    pub async fn sell(&mut self, item: &str, price: f64, timestamp: i64, qty: Option<f64>) {
+        // 持有多头时先减多头, 超过的部分开空
+        let margin = self.order_margin(qty);
+        self.place_order(Order::market(item, -margin/price, price, timestamp)).await;
+    }
+    // 以下按 item 最新 K 线的收盘价下市价单, 调整到目标仓位, 不需要调整或没有行情时返回 None
+    // 平掉全部持仓
+    pub async fn close_position(&mut self, item: &str) -> Option<u64> {
+        self.order_target_quantity(item, 0.0).await
+    }
+    // 反手: 平掉持仓并反向开同样数量
+    pub async fn reverse_position(&mut self, item: &str) -> Option<u64> {
+        let size = self.context.get_position(item).map(|p| p.size).unwrap_or(0.0);
+        self.order_target_quantity(item, -size).await
+    }
+    // 调整持仓到 target 数量, 负数为空头
+    pub async fn order_target_quantity(&mut self, item: &str, target: f64) -> Option<u64> {
+        let candle = match self.context.candles.get(item).and_then(|c| c.last()) {
+            Some(candle) => candle.clone(),
+            None => {
+                log::error!("{} no candle to order target {}", item, target);
+                return None;
+            }
+        };
+        let size = self.context.get_position(item).map(|p| p.size).unwrap_or(0.0);
+        let qty = target - size;
+        if qty.abs() <= f64::EPSILON*size.abs().max(1.0) {
+            return None;
+        }
+        Some(self.place_order(Order::market(item, qty, candle.close, candle.timestamp)).await)
+    }
+    // 调整持仓到 target 金额(按最新收盘价), 负数为空头
+    pub async fn order_target_value(&mut self, item: &str, target: f64) -> Option<u64> {
+        let price = self.context.candles.get(item).and_then(|c| c.last()).map(|c| c.close)?;
+        self.order_target_quantity(item, target/price).await
+    }
+    // 调整持仓到权益的 percent 比例, 如 0.5 为半仓多, -1.0 为全仓空
+    pub async fn order_target_percent(&mut self, item: &str, percent: f64) -> Option<u64> {
+        let timestamp = self.context.candles.get(item).and_then(|c| c.last()).map(|c| c.timestamp)?;
+        let equity = self.account_equity(item, timestamp).equity_value;
+        self.order_target_value(item, equity*percent).await
+    }
+    // 下单: SameClose 时市价单立即按 price 成交, 其他订单挂在订单簿中, 从下一根 K 线开始撮合
+    // 返回订单 id
+    pub async fn place_order(&mut self, order: Order) -> u64 {
+        let is_valid = order.qty != 0.0
+            && match order.order_type {
+                OrderType::Market | OrderType::Limit => order.price > 0.0,
+                OrderType::Stop => order.stop_price > 0.0,
+                OrderType::StopLimit => order.stop_price > 0.0 && order.price > 0.0,
             };
-            self.process_order(&order).await;
-            let equity = Equity{
-                item: item.to_string(),
-                timestamp,
-                equity_value: last_equity.equity_value-margin*self.params.trading_fee,
-                close_latest: price,
-                pos_size: if last_equity.pos_size < 0.0 { last_equity.pos_size+qty } else { qty },
-                cash_aval: last_equity.cash_aval-margin*(1.00+self.params.trading_fee),
+        let order = Order{
+            id: self.context.next_order_id(),
+            status: OrderStatus::New,
+            filled_qty: 0.0,
+            ..order
+        };
+        let id = order.id;
+        if !is_valid {
+            log::error!("invalid order: {:?}", order);
+            self.reject_order(order);
+            return id;
+        }
+        if order.order_type == OrderType::Market && self.params.fill_model == FillModel::SameClose {
+            // 按当前 K 线收盘成交, 时间取收盘时间, 与 mark_to_market 的权益快照一致
+            let last_candle = self.context.candles.get(&order.item).and_then(|c| c.last());
+            let volume = last_candle.map(|c| c.volume);
+            let timestamp = last_candle
+                .map(|c| common::interval_close_time(c.timestamp, &c.interval))
+                .unwrap_or(order.timestamp);
+            let price = order.price;
+            let qty = self.fill_qty(&order, volume);
+            let order = if qty == 0.0 {
+                // 本根 K 线不能成交, 和挂单一样先推送 New
+                self.emit_order(order.clone());
+                Some(order)
+            } else {
+                let fill = self.make_fill(&order, qty, price, timestamp);
+                self.fill_order(order, fill).await
             };
-            let _ = self.broker.event_sender.send(Event::EventEquity(equity));
+            // 超过成交量限制的部分从下一根 K 线继续成交
+            if let Some(order) = order.filter(|o| o.status.is_open()) {
+                self.context.push_order(order);
+            }
+            return id;
         }
-        
+        self.context.push_order(order.clone());
+        self.emit_order(order);
+        id
+    }
+    fn reject_order(&mut self, order: Order) {
+        let order = Order{
+            id: if order.id == 0 { self.context.next_order_id() } else { order.id },
+            status: OrderStatus::Rejected,
+            ..order
+        };
+        self.emit_order(order);
+    }
+    // 撤销挂单, 订单不存在或已结束时返回 false
+    pub fn cancel_order(&mut self, id: u64) -> bool {
+        match self.context.remove_order(id) {
+            Some(order) => {
+                let order = Order{
+                    status: OrderStatus::Cancelled,
+                    ..order
+                };
+                self.emit_order(order);
+                true
+            }
+            None => false,
+        }
+    }
+    // 修改挂单的数量/限价/触发价, None 表示不变, 新数量不能小于已成交数量
+    pub fn modify_order(&mut self, id: u64, qty: Option<f64>, price: Option<f64>, stop_price: Option<f64>) -> bool {
+        let order = match self.context.orders.values_mut().flatten().find(|o| o.id == id) {
+            Some(order) => order,
+            None => return false,
+        };
+        let qty = qty.unwrap_or(order.qty);
+        let price = price.unwrap_or(order.price);
+        let stop_price = stop_price.unwrap_or(order.stop_price);
+        let is_valid = qty*order.qty > 0.0
+            && qty.abs() > order.filled_qty.abs()
+            && match order.order_type {
+                OrderType::Market | OrderType::Limit => price > 0.0,
+                OrderType::Stop => stop_price > 0.0,
+                OrderType::StopLimit => stop_price > 0.0 && price > 0.0,
+            };
+        if !is_valid {
+            log::error!("invalid modify of order {}: qty {}, price {}, stop_price {}", id, qty, price, stop_price);
+            return false;
+        }
+        order.qty = qty;
+        order.price = price;
+        order.stop_price = stop_price;
+        let order = order.clone();
+        self.emit_order(order);
+        true
+    }
+    // qty 中新开仓的部分, 反向的部分先用于平仓
+    fn opening_qty(&self, item: &str, qty: f64) -> f64 {
+        let size = self.context.get_position(item).map(|p| p.size).unwrap_or(0.0);
+        if size*qty >= 0.0 {
+            qty
+        } else if qty.abs() > size.abs() {
+            qty + size
+        } else {
+            0.0
+        }
+    }
+    // 用新 K 线撮合之前的挂单: 先处理过期, 再按触发价/限价成交, 跳空时按开盘价成交
+    async fn match_orders(&mut self, candle: &Candle) {
+        let item = format!("{}_{}", candle.symbol, candle.interval);
+        let orders = match self.context.orders.remove(&item) {
+            Some(orders) if !orders.is_empty() => orders,
+            _ => return,
+        };
+        let mut resting = vec![];
+        for mut order in orders {
+            if order.expire_at > 0 && candle.timestamp >= order.expire_at {
+                order.status = OrderStatus::Expired;
+                order.timestamp = candle.timestamp;
+                self.emit_order(order);
+                continue;
+            }
+            let is_buy = order.qty > 0.0;
+            let mut price = None;
+            if order.order_type == OrderType::Market {
+                price = Some(match self.params.fill_model {
+                    FillModel::SameClose | FillModel::NextOpen => candle.open,
+                    FillModel::NextOhlcAvg => (candle.open + candle.high + candle.low + candle.close)/4.0,
+                    FillModel::WorstOfBar => if is_buy { candle.high } else { candle.low },
+                });
+            } else if matches!(order.order_type, OrderType::Stop | OrderType::StopLimit) {
+                let triggered = if is_buy { candle.high >= order.stop_price } else { candle.low <= order.stop_price };
+                if !triggered {
+                    resting.push(order);
+                    continue;
+                }
+                let trigger_price = if is_buy { candle.open.max(order.stop_price) } else { candle.open.min(order.stop_price) };
+                let within_limit = (is_buy && trigger_price <= order.price) || (!is_buy && trigger_price >= order.price);
+                if order.order_type == OrderType::Stop || within_limit {
+                    price = Some(trigger_price);
+                } else {
+                    // 触发后无法确定同一根 K 线内的先后, 从下一根开始按限价单撮合
+                    order.order_type = OrderType::Limit;
+                }
+            } else if order.order_type == OrderType::Limit {
+                if is_buy && candle.low <= order.price {
+                    price = Some(candle.open.min(order.price));
+                } else if !is_buy && candle.high >= order.price {
+                    price = Some(candle.open.max(order.price));
+                }
+            }
+            let qty = self.fill_qty(&order, Some(candle.volume));
+            let price = match price {
+                Some(price) if qty != 0.0 => price,
+                _ => {
+                    resting.push(order);
+                    continue;
+                }
+            };
+            let fill = self.make_fill(&order, qty, price, candle.timestamp);
+            if let Some(mut order) = self.fill_order(order, fill).await {
+                if order.status.is_open() {
+                    // 已触发的止损单剩余部分按市价/限价继续成交
+                    order.order_type = match order.order_type {
+                        OrderType::Stop => OrderType::Market,
+                        OrderType::StopLimit => OrderType::Limit,
+                        order_type => order_type,
+                    };
+                    resting.push(order);
+                }
+            }
+        }
+        if !resting.is_empty() {
+            self.context.orders.insert(item, resting);
+        }
+    }
+    // 本次最多成交的数量: 剩余数量, 开启成交量限制时不超过 K 线成交量的 max_volume_participation
+    fn fill_qty(&self, order: &Order, volume: Option<f64>) -> f64 {
+        let remaining = order.qty - order.filled_qty;
+        match volume {
+            Some(volume) if self.params.max_volume_participation > 0.0 => {
+                remaining.signum()*remaining.abs().min(volume*self.params.max_volume_participation)
+            }
+            _ => remaining,
+        }
+    }
+    // 订单的 qty 数量按 price 在 timestamp 成交, 市价单和止损单按币种的滑点模型调整成交价,
+    // 限价单按 maker 费率收手续费, 其他按 taker
+    fn make_fill(&mut self, order: &Order, qty: f64, price: f64, timestamp: i64) -> Fill {
+        let symbol = order.item.rsplit_once('_').map(|(symbol, _)| symbol).unwrap_or(&order.item);
+        let offset = if matches!(order.order_type, OrderType::Market | OrderType::Stop) {
+            let candle = self.context.candles.get(&order.item).and_then(|candles| candles.last());
+            let atr = self.context.get_atr(&order.item);
+            self.params.slippage.for_symbol(symbol).price_offset(price, qty, atr, candle)
+        } else {
+            0.0
+        };
+        let fill_price = (price + qty.signum()*offset).max(0.0);
+        let is_maker = order.order_type == OrderType::Limit;
+        let volume_30d = self.context.volume_30d.total(timestamp);
+        let fee_rate = self.fee_schedule.rate(symbol, is_maker, volume_30d);
+        Fill {
+            qty,
+            price: fill_price,
+            slippage: (fill_price - price).abs()*qty.abs(),
+            fee: (fill_price*qty).abs()*fee_rate,
+            timestamp,
+        }
+    }
+}
+
+// 订单的一次成交
+struct Fill {
+    qty: f64,
+    price: f64,
+    slippage: f64,
+    fee: f64,
+    timestamp: i64,
+}
+
+// 累加成交到订单, 全部成交为 Filled, 否则为 PartiallyFilled
+fn apply_fill(order: Order, fill: &Fill) -> Order {
+    let filled_qty = order.filled_qty + fill.qty;
+    let is_filled = filled_qty.abs() >= order.qty.abs()*(1.0 - 1e-9);
+    Order {
+        status: if is_filled { OrderStatus::Filled } else { OrderStatus::PartiallyFilled },
+        fill_price: (order.fill_price*order.filled_qty + fill.price*fill.qty)/filled_qty,
+        filled_qty,
+        slippage: order.slippage + fill.slippage,
+        fee: order.fee + fill.fee,
+        timestamp: fill.timestamp,
+        ..order
     }
 }
 //####blockcode1 end####
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drg::source::CandleStream;
+    use std::fs;
+
+    const ITEM: &str = "BTCUSDT_1h";
+    const HOUR: i64 = 60*60*1000;
+
+    fn params(fill_model: FillModel) -> StrategyParams {
+        StrategyParams {
+            stg_name: "test".to_string(),
+            window_length: 10,
+            window_atr: 10,
+            symbols: vec!["BTCUSDT".to_string()],
+            intervals: vec!["1h".to_string()],
+            is_use_percent_of_equity: false,
+            percent_of_equity: 0.5,
+            percent_of_every_trade_money: 0.1,
+            is_sl: false,
+            n_atr_sl: 2.0,
+            is_tp: false,
+            n_atr_tp: 5.0,
+            tp_method: "".to_string(),
+            stop_fill: StopFill::GapOpen,
+            fill_model,
+            max_volume_participation: 0.0,
+            slippage: SlippageConfig::default(),
+            slippage_file: "".to_string(),
+            trailing_stop: TrailingStop::None,
+            benchmark: Benchmark::None,
+            initial_capital: 10000.0,
+            is_portfolio: false,
+            items_timestamp_start: HashMap::from([(ITEM.to_string(), 0)]),
+            items_timestamp_end: HashMap::new(),
+            trading_fee: 0.001,
+            fee_schedule: None,
+            fee_file: "".to_string(),
+            data_dir: "".to_string(),
+            cache_dir: "".to_string(),
+            results_dir: "".to_string(),
+            results_db: "".to_string(),
+        }
+    }
+
+    // 初始化资金和空仓位, 测试中不读取行情, 数据源只用于构造
+    async fn strategy(fill_model: FillModel) -> Strategy {
+        let source = Arc::new(BinanceCsvSource::new(std::env::temp_dir()));
+        let mut stg = Strategy::with_data_source(params(fill_model), source);
+        stg.init().await;
+        stg
+    }
+
+    fn candle(timestamp: i64, close: f64) -> Candle {
+        Candle {
+            symbol: "BTCUSDT".to_string(),
+            timestamp,
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 1000.0,
+            interval: "1h".to_string(),
+        }
+    }
+
+    // K 线走完: 先加入 context 再按收盘价估值, 与 run 中的顺序一致
+    fn close_candle(stg: &mut Strategy, candle: Candle) {
+        stg.context.push_candle(candle.clone());
+        stg.mark_to_market(&candle);
+    }
+
+    // 新 K 线: 与 handle_events 的顺序一致, 先撮合挂单和止损止盈再估值
+    async fn run_candle(stg: &mut Strategy, candle: Candle) {
+        stg.context.push_candle(candle.clone());
+        stg.match_orders(&candle).await;
+        stg.check_stops(&candle).await;
+        stg.update_trailing_stop(&candle);
+        stg.mark_to_market(&candle);
+    }
+
+    // 订单 id 的最新状态
+    fn order(stg: &Strategy, id: u64) -> &Order {
+        stg.context.order_history.iter().rev().find(|o| o.id == id).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    // 收盘后按收盘价下 qty 数量的市价单
+    async fn trade(stg: &mut Strategy, timestamp: i64, price: f64, qty: f64) {
+        close_candle(stg, candle(timestamp, price));
+        stg.place_order(Order::market(ITEM, qty, price, timestamp)).await;
+    }
+
+    fn last_equity(stg: &Strategy) -> &Equity {
+        stg.context.equities[ITEM].last().unwrap()
+    }
+
+    #[tokio::test]
+    async fn same_close_equity_timestamps_never_decrease() {
+        let mut stg = strategy(FillModel::SameClose).await;
+        close_candle(&mut stg, candle(0, 100.0));
+        stg.buy(ITEM, 100.0, 0, None).await;
+        close_candle(&mut stg, candle(HOUR, 110.0));
+        stg.sell(ITEM, 110.0, HOUR, None).await;
+        close_candle(&mut stg, candle(2*HOUR, 105.0));
+
+        let timestamps: Vec<i64> = stg.context.equities[ITEM].iter().map(|e| e.timestamp).collect();
+        assert!(timestamps.windows(2).all(|w| w[0] <= w[1]), "{:?}", timestamps);
+        // 成交时间为 K 线收盘时间
+        assert_eq!(stg.context.order_history[0].timestamp, HOUR - 1);
+        let trade = &stg.context.trade_records[ITEM][0];
+        assert_eq!((trade.time_open, trade.time_close), (HOUR - 1, 2*HOUR - 1));
+    }
+
+    // 手续费 0.001, 无滑点: 开多 10@100 后按 110 减仓 4, 再按 120 全部平仓
+    #[tokio::test]
+    async fn reduce_then_close() {
+        let mut stg = strategy(FillModel::SameClose).await;
+        trade(&mut stg, 0, 100.0, 10.0).await;
+        let equity = last_equity(&stg);
+        assert_close(equity.cash_aval, 10000.0 - 1000.0 - 1.0);
+        assert_close(equity.realized_pnl, -1.0);
+
+        // 减仓: 持仓数量减少, 均价不变, 平掉的部分分摊开仓手续费
+        trade(&mut stg, HOUR, 110.0, -4.0).await;
+        let position = stg.context.get_position(ITEM).unwrap();
+        assert_close(position.size, 6.0);
+        assert_close(position.price, 100.0);
+        let equity = last_equity(&stg);
+        assert_close(equity.cash_aval, 8999.0 + 400.0 + 40.0 - 0.44);
+        assert_close(equity.realized_pnl, -1.0 + 40.0 - 0.44);
+        let records = &stg.context.trade_records[ITEM];
+        assert_eq!(records.len(), 2);
+        assert_close(records[0].size, 4.0);
+        assert_close(records[0].pnl, 40.0);
+        assert_close(records[0].fee, 0.4 + 0.44);
+        assert_eq!(records[0].time_close, 2*HOUR - 1);
+        assert_close(records[1].size, 6.0);
+        assert_close(records[1].fee, 0.6);
+        assert_eq!(records[1].time_close, 0);
+
+        // 全部平仓: 资金只剩现金, 等于初始资金加上净盈亏
+        trade(&mut stg, 2*HOUR, 120.0, -6.0).await;
+        let position = stg.context.get_position(ITEM).unwrap();
+        assert_close(position.size, 0.0);
+        assert_close(position.price, 0.0);
+        let equity = last_equity(&stg);
+        assert_close(equity.cash_aval, 10000.0 + 40.0 + 120.0 - 1.0 - 0.44 - 0.72);
+        assert_close(equity.equity_value, equity.cash_aval);
+        assert_close(equity.realized_pnl, 40.0 + 120.0 - 1.0 - 0.44 - 0.72);
+        let records = &stg.context.trade_records[ITEM];
+        assert_eq!(records.len(), 2);
+        assert_close(records[1].pnl, 120.0);
+        assert_close(records[1].fee, 0.6 + 0.72);
+        assert_eq!(records[1].label_close, "Close");
+        assert!(stg.context.order_history.iter().all(|o| o.status == OrderStatus::Filled));
+    }
+
+    // 开多 10@100 后按 90 卖出 15: 平掉 10 个多头, 反手开空 5 个, 手续费按数量分摊
+    #[tokio::test]
+    async fn flip_long_to_short() {
+        let mut stg = strategy(FillModel::SameClose).await;
+        trade(&mut stg, 0, 100.0, 10.0).await;
+        trade(&mut stg, HOUR, 90.0, -15.0).await;
+
+        let position = stg.context.get_position(ITEM).unwrap();
+        assert_close(position.size, -5.0);
+        assert_close(position.price, 90.0);
+        let equity = last_equity(&stg);
+        assert_close(equity.cash_aval, 8999.0 + 1000.0 - 100.0 - 450.0 - 1.35);
+        assert_close(equity.realized_pnl, -1.0 - 100.0 - 1.35);
+        assert_close(equity.equity_value, 10000.0 - 100.0 - 1.0 - 1.35);
+
+        let records = &stg.context.trade_records[ITEM];
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].side, "buy");
+        assert_close(records[0].size, 10.0);
+        assert_close(records[0].pnl, -100.0);
+        assert_close(records[0].fee, 1.0 + 0.9);
+        assert_eq!(records[1].side, "sell");
+        assert_close(records[1].size, -5.0);
+        assert_close(records[1].price_open, 90.0);
+        assert_close(records[1].fee, 0.45);
+        assert_eq!(records[1].time_close, 0);
+    }
+
+    // 成交量为 0 时本根 K 线不成交, 订单先推送 New 再挂到订单簿
+    #[tokio::test]
+    async fn same_close_unfilled_order_emits_new() {
+        let mut stg = strategy(FillModel::SameClose).await;
+        stg.params.max_volume_participation = 0.1;
+        close_candle(&mut stg, Candle { volume: 0.0, ..candle(0, 100.0) });
+        let id = stg.place_order(Order::market(ITEM, 1.0, 100.0, 0)).await;
+
+        assert_eq!(stg.context.order_history.len(), 1);
+        assert_eq!(stg.context.order_history[0].id, id);
+        assert_eq!(stg.context.order_history[0].status, OrderStatus::New);
+        assert!(stg.context.get_order(id).is_some());
+    }
+
+    // 读取行情时 panic 的数据源
+    #[derive(Debug)]
+    struct PanicSource;
+
+    impl DataSource for PanicSource {
+        fn stream_candles(&self, _symbol: &str, _interval: &str, _timestamp_start: i64, _timestamp_end: i64) -> CandleStream {
+            panic!("data source failed");
+        }
+    }
+
+    // broker 任务没有发送 EventFinish 就退出时, 回测结束并报告 broker 的错误, 而不是一直等待
+    #[tokio::test]
+    #[should_panic(expected = "Broker task failed")]
+    async fn run_ends_when_broker_panics() {
+        let mut stg = Strategy::with_data_source(params(FillModel::NextOpen), Arc::new(PanicSource));
+        if tokio::time::timeout(std::time::Duration::from_secs(10), stg.run()).await.is_err() {
+            panic!("run did not finish");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "load slippage")]
+    fn missing_slippage_file_fails() {
+        let params = StrategyParams { slippage_file: "/nonexistent/slippage.json".to_string(), ..params(FillModel::SameClose) };
+        Strategy::with_data_source(params, Arc::new(PanicSource));
+    }
+
+    #[test]
+    #[should_panic(expected = "load fee schedule")]
+    fn invalid_fee_file_fails() {
+        let path = std::env::temp_dir().join(format!("fee_test_{}.json", std::process::id()));
+        fs::write(&path, "{\"default\": 0.001}").unwrap();
+        let params = StrategyParams { fee_file: path.to_string_lossy().to_string(), ..params(FillModel::SameClose) };
+        let result = std::panic::catch_unwind(|| Strategy::with_data_source(params, Arc::new(PanicSource)));
+        let _ = fs::remove_file(&path);
+        if let Err(e) = result {
+            std::panic::resume_unwind(e);
+        }
+    }
+
+    // 限价单: 没触及限价时继续挂着, 跳空低开时按开盘价成交
+    #[tokio::test]
+    async fn limit_fills_at_open_on_gap() {
+        let mut stg = strategy(FillModel::NextOpen).await;
+        close_candle(&mut stg, candle(0, 100.0));
+        let id = stg.place_order(Order::limit(ITEM, 1.0, 95.0, 0)).await;
+        run_candle(&mut stg, Candle { low: 96.0, ..candle(HOUR, 100.0) }).await;
+        assert_eq!(order(&stg, id).status, OrderStatus::New);
+        assert!(stg.context.get_order(id).is_some());
+
+        run_candle(&mut stg, Candle { open: 90.0, high: 92.0, low: 88.0, ..candle(2*HOUR, 91.0) }).await;
+        let filled = order(&stg, id);
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_close(filled.fill_price, 90.0);
+        assert_eq!(filled.timestamp, 2*HOUR);
+        assert!(stg.context.get_order(id).is_none());
+        let position = stg.context.get_position(ITEM).unwrap();
+        assert_close(position.size, 1.0);
+        assert_close(position.price, 90.0);
+    }
+
+    // 止损单: 盘中触发按触发价成交, 跳空越过触发价时按开盘价成交
+    #[tokio::test]
+    async fn stop_fills_at_trigger_or_gap_open() {
+        let mut stg = strategy(FillModel::NextOpen).await;
+        close_candle(&mut stg, candle(0, 100.0));
+        let buy = stg.place_order(Order::stop(ITEM, 1.0, 105.0, 0)).await;
+        let sell = stg.place_order(Order::stop(ITEM, -1.0, 95.0, 0)).await;
+        run_candle(&mut stg, Candle { high: 106.0, low: 99.0, ..candle(HOUR, 100.0) }).await;
+        assert_close(order(&stg, buy).fill_price, 105.0);
+        assert_eq!(order(&stg, sell).status, OrderStatus::New);
+
+        run_candle(&mut stg, Candle { open: 90.0, high: 91.0, low: 89.0, ..candle(2*HOUR, 90.0) }).await;
+        let filled = order(&stg, sell);
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_close(filled.fill_price, 90.0);
+        assert_close(stg.context.get_position(ITEM).unwrap().size, 0.0);
+    }
+
+    // 止损限价单: 触发价在限价内时按触发价成交, 开盘跳空越过限价时变成限价单, 从下一根 K 线开始撮合
+    #[tokio::test]
+    async fn stop_limit_becomes_limit_when_open_gaps_past_limit() {
+        let mut stg = strategy(FillModel::NextOpen).await;
+        close_candle(&mut stg, candle(0, 100.0));
+        let within = stg.place_order(Order::stop_limit(ITEM, 1.0, 105.0, 106.0, 0)).await;
+        run_candle(&mut stg, Candle { high: 105.5, ..candle(HOUR, 100.0) }).await;
+        assert_close(order(&stg, within).fill_price, 105.0);
+
+        let gapped = stg.place_order(Order::stop_limit(ITEM, 1.0, 110.0, 111.0, HOUR)).await;
+        run_candle(&mut stg, Candle { open: 115.0, high: 116.0, low: 112.0, ..candle(2*HOUR, 114.0) }).await;
+        let resting = stg.context.get_order(gapped).unwrap();
+        assert_eq!(resting.order_type, OrderType::Limit);
+        assert_close(resting.filled_qty, 0.0);
+
+        run_candle(&mut stg, Candle { open: 113.0, high: 113.0, low: 110.0, ..candle(3*HOUR, 110.0) }).await;
+        let filled = order(&stg, gapped);
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_close(filled.fill_price, 111.0);
+    }
+
+    // 到达 expire_at 的挂单过期, 不再成交
+    #[tokio::test]
+    async fn resting_order_expires() {
+        let mut stg = strategy(FillModel::NextOpen).await;
+        close_candle(&mut stg, candle(0, 100.0));
+        let id = stg.place_order(Order { expire_at: 2*HOUR, ..Order::limit(ITEM, 1.0, 90.0, 0) }).await;
+        run_candle(&mut stg, Candle { low: 95.0, ..candle(HOUR, 100.0) }).await;
+        assert_eq!(order(&stg, id).status, OrderStatus::New);
+
+        run_candle(&mut stg, Candle { low: 80.0, ..candle(2*HOUR, 85.0) }).await;
+        let expired = order(&stg, id);
+        assert_eq!(expired.status, OrderStatus::Expired);
+        assert_eq!(expired.timestamp, 2*HOUR);
+        assert!(stg.context.get_order(id).is_none());
+        assert_close(stg.context.get_position(ITEM).unwrap().size, 0.0);
+    }
+
+    // 成交量限制下止损单部分成交后, 剩余部分变成市价单按下一根 K 线开盘价成交
+    #[tokio::test]
+    async fn partially_filled_stop_becomes_market() {
+        let mut stg = strategy(FillModel::NextOpen).await;
+        stg.params.max_volume_participation = 0.1;
+        close_candle(&mut stg, candle(0, 100.0));
+        let id = stg.place_order(Order::stop(ITEM, 15.0, 105.0, 0)).await;
+        run_candle(&mut stg, Candle { high: 106.0, volume: 100.0, ..candle(HOUR, 100.0) }).await;
+        let partial = stg.context.get_order(id).unwrap();
+        assert_eq!(partial.status, OrderStatus::PartiallyFilled);
+        assert_eq!(partial.order_type, OrderType::Market);
+        assert_close(partial.filled_qty, 10.0);
+        assert_close(partial.fill_price, 105.0);
+
+        run_candle(&mut stg, Candle { open: 107.0, volume: 100.0, ..candle(2*HOUR, 108.0) }).await;
+        let filled = order(&stg, id);
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_close(filled.filled_qty, 15.0);
+        assert_close(filled.fill_price, (10.0*105.0 + 5.0*107.0)/15.0);
+        assert_close(stg.context.get_position(ITEM).unwrap().size, 15.0);
+    }
+
+    // 止损 = 开仓价 - 2 倍 ATR = 90: GapOpen 跳空时按开盘价成交, StopPrice 总是按止损价成交
+    #[tokio::test]
+    async fn stop_loss_fill_price_by_stop_fill() {
+        for (stop_fill, price) in [(StopFill::GapOpen, 85.0), (StopFill::StopPrice, 90.0)] {
+            let mut stg = strategy(FillModel::SameClose).await;
+            stg.params.is_sl = true;
+            stg.params.stop_fill = stop_fill;
+            stg.context.update_atr(ITEM, 5.0);
+            trade(&mut stg, 0, 100.0, 10.0).await;
+            assert_close(stg.context.get_position(ITEM).unwrap().stop_loss, 90.0);
+
+            run_candle(&mut stg, Candle { open: 85.0, high: 86.0, low: 80.0, ..candle(HOUR, 82.0) }).await;
+            assert_close(stg.context.get_position(ITEM).unwrap().size, 0.0);
+            let record = &stg.context.trade_records[ITEM][0];
+            assert_eq!(record.label_close, "StopLoss");
+            assert_close(record.price_close, price);
+            assert_close(record.pnl, (price - 100.0)*10.0);
+        }
+    }
+
+    // 止盈 = 开仓价的 110%: 盘中触及按止盈价成交, GapOpen 跳空高开时按开盘价成交
+    #[tokio::test]
+    async fn take_profit_fill_price() {
+        for (open, price) in [(105.0, 110.0), (115.0, 115.0)] {
+            let mut stg = strategy(FillModel::SameClose).await;
+            stg.params.is_tp = true;
+            stg.take_profit = TakeProfit::Percent(0.1);
+            trade(&mut stg, 0, 100.0, 10.0).await;
+            assert_close(stg.context.get_position(ITEM).unwrap().take_profit, 110.0);
+
+            run_candle(&mut stg, Candle { open, high: 116.0, low: open - 1.0, ..candle(HOUR, 112.0) }).await;
+            assert_close(stg.context.get_position(ITEM).unwrap().size, 0.0);
+            let record = &stg.context.trade_records[ITEM][0];
+            assert_eq!(record.label_close, "TakeProfit");
+            assert_close(record.price_close, price);
+        }
+    }
+
+    // 跟踪止损距最高价 10%, 只随新高上移, 回落时不下移
+    #[tokio::test]
+    async fn trailing_stop_ratchets_and_closes() {
+        let mut stg = strategy(FillModel::SameClose).await;
+        stg.params.trailing_stop = TrailingStop::Percent(0.1);
+        trade(&mut stg, 0, 100.0, 10.0).await;
+        assert_close(stg.context.get_position(ITEM).unwrap().trailing_stop, 90.0);
+
+        run_candle(&mut stg, Candle { open: 110.0, high: 120.0, low: 110.0, ..candle(HOUR, 115.0) }).await;
+        assert_close(stg.context.get_position(ITEM).unwrap().trailing_stop, 108.0);
+        run_candle(&mut stg, Candle { open: 112.0, high: 112.0, low: 109.0, ..candle(2*HOUR, 110.0) }).await;
+        let position = stg.context.get_position(ITEM).unwrap();
+        assert_close(position.size, 10.0);
+        assert_close(position.trailing_stop, 108.0);
+
+        // 跳空低于跟踪止损, 按开盘价成交
+        run_candle(&mut stg, Candle { open: 107.0, high: 107.0, low: 105.0, ..candle(3*HOUR, 106.0) }).await;
+        assert_close(stg.context.get_position(ITEM).unwrap().size, 0.0);
+        let record = &stg.context.trade_records[ITEM][0];
+        assert_eq!(record.label_close, "TrailingStop");
+        assert_close(record.price_close, 107.0);
+    }
+
+    // 分批止盈: +5% 平 30%, +10% 平 30%, +20% 平掉剩余, 每档后止盈价移到下一档
+    #[tokio::test]
+    async fn ladder_take_profit_scales_out() {
+        let mut stg = strategy(FillModel::SameClose).await;
+        stg.params.is_tp = true;
+        stg.take_profit = "ladder_0.05:0.3,0.1:0.3,0.2:0.4".parse().unwrap();
+        trade(&mut stg, 0, 100.0, 10.0).await;
+        assert_close(stg.context.get_position(ITEM).unwrap().take_profit, 105.0);
+
+        // (止盈价, 平掉的数量, 剩余数量, 下一档止盈价)
+        let steps = [(105.0, 3.0, 7.0, 110.0), (110.0, 3.0, 4.0, 120.0), (120.0, 4.0, 0.0, 0.0)];
+        for (i, (price, closed, size, next_take_profit)) in steps.into_iter().enumerate() {
+            let timestamp = (i as i64 + 1)*HOUR;
+            run_candle(&mut stg, Candle { open: price - 1.0, high: price + 1.0, low: price - 2.0, ..candle(timestamp, price) }).await;
+            let position = stg.context.get_position(ITEM).unwrap();
+            assert_close(position.size, size);
+            if size > 0.0 {
+                assert_close(position.take_profit, next_take_profit);
+            }
+            let record = stg.context.trade_records[ITEM].iter().rev().find(|t| t.time_close > 0).unwrap();
+            assert_eq!(record.label_close, "TakeProfit");
+            assert_close(record.price_close, price);
+            assert_close(record.size, closed);
+        }
+    }
+}
@@ -9,7 +9,8 @@ use std::collections::HashMap;
 mod drg;
 mod utils;
 use drg::{
-    model::{Candle, Equity, Order, Position, TradeRecord, StrategyParams},
+    model::{Candle, Equity, Order, Position, TradeRecord, StrategyParams, FillModel, StopFill, TrailingStop, Benchmark},
+    slippage::SlippageConfig,
     strategy::{IStgHandler, Strategy},
 };
 use utils::{logger, common};
@@ -121,12 +122,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         is_tp: false,
         n_atr_tp: 5.0,
         tp_method: "percent_0.23".to_string(),
+        stop_fill: StopFill::GapOpen,
+        fill_model: FillModel::NextOpen,
+        max_volume_participation: 0.0,
+        slippage: SlippageConfig::default(),
+        slippage_file: "".to_string(),
+        trailing_stop: TrailingStop::None,
+        benchmark: Benchmark::BuyAndHold,
         symbols,
         intervals,
         initial_capital,
+        is_portfolio: false,
         items_timestamp_start,
         items_timestamp_end,
         trading_fee: 0.001,
+        fee_schedule: None,
+        fee_file: "".to_string(),
+        data_dir: "".to_string(),
+        cache_dir: "cache".to_string(),
+        results_dir: "results".to_string(),
+        results_db: "backtests".to_string(),
     };
     
     let mut stg = Strategy::new(params);
@@ -9,7 +9,8 @@ use async_trait::async_trait;
 use std::collections::HashMap;
 mod drg;
 mod utils;
-use drg::model::{Candle, Equity, Order, Position, TradeRecord, StrategyParams};
+use drg::model::{Candle, Equity, Order, OrderStatus, Position, TradeRecord, StrategyParams, FillModel, StopFill, TrailingStop, Benchmark};
+use drg::slippage::SlippageConfig;
 use drg::strategy::{IStgHandler, Strategy};
 use utils::{logger, common};
 use polars::prelude::{DataFrame, Series, NamedFrom};
@@ -93,13 +94,20 @@ impl IStgHandler for Strategy {
     }
     async fn on_finish(&mut self) {
         log::info!("on_finish");
+        for profit in &self.context.profits {
+            log::info!("{},return:{:.4},cagr:{:.4},sharpe:{:.2},mdd:{:.4},trades:{},win_rate:{:.2}",
+                profit.item,
+                profit.total_return,
+                profit.cagr,
+                profit.sharpe,
+                profit.max_drawdown,
+                profit.trades,
+                profit.win_rate
+            );
+        }
     }
     async fn on_candle(&mut self, candle: &Candle) {
         let item = format!("{}_{}", candle.symbol, candle.interval);
-        let close = candle.close;
-        let high = candle.high;
-        let low = candle.low;
-
         let timestamp_millis = candle.timestamp;
         let len = self
             .context
@@ -139,19 +147,24 @@ impl IStgHandler for Strategy {
             }
             let highs: Vec<f64> = candles.iter().map(|c| c.high).collect();
             let lows: Vec<f64> = candles.iter().map(|c| c.low).collect();
+            // 在通道上下轨挂突破止损单, 只在下一根 K 线有效
+            let expire_at = timestamp_millis + 2*common::interval_millis(&candle.interval).unwrap_or(0);
+            let margin = self.order_margin(Some(100.00));
             let max = common::find_max_last_n(&highs, 20);
             if max > 0.0 {
                 // println!("{}, MAX_{}:{:?}", item, period, value);
-                if high == max && pos_size <= 0.0 {
-                    self.buy(&item, close, timestamp_millis, Some(100.00)).await;
+                if pos_size <= 0.0 {
+                    let order = Order::stop(&item, margin/max - pos_size, max, timestamp_millis);
+                    self.place_order(Order{ expire_at, ..order }).await;
                 }
             }
             
             let min = common::find_min_last_n(&lows, 20);
             if min > 0.0 {
                 // println!("{}, MIN_{}:{:?}", item, period, value);
-                if low == min && pos_size >= 0.0 {
-                    self.sell(&item, close, timestamp_millis, Some(100.00)).await;
+                if pos_size >= 0.0 {
+                    let order = Order::stop(&item, -margin/min - pos_size, min, timestamp_millis);
+                    self.place_order(Order{ expire_at, ..order }).await;
                 }
             }
             
@@ -177,6 +190,9 @@ impl IStgHandler for Strategy {
         // 将3天转换为毫秒
         let three_days_millis = 3 * 24 * 60 * 60 * 1000;
 
+        if order.status != OrderStatus::Filled {
+            return;
+        }
         let datetime = common::timestamp_millis_to_datetime(order.timestamp);
 
         let action = if order.qty > 0.00 {
@@ -184,7 +200,7 @@ impl IStgHandler for Strategy {
         } else {
             "Short"
         };
-        log::info!("{},{}@{},{}", datetime.format("%Y-%m-%d %H:%M:%S%.3f UTC"), action, order.item, order.price);
+        log::info!("{},{}@{},{}", datetime.format("%Y-%m-%d %H:%M:%S%.3f UTC"), action, order.item, order.fill_price);
         // // 判断差值是否大于3天
         // if diff_millis < three_days_millis {
         //     log::info!("{},{}@{},{}", datetime.format("%Y-%m-%d %H:%M:%S%.3f UTC"), action, order.item, order.price);
@@ -243,12 +259,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         is_tp: false,
         n_atr_tp: 5.00,
         tp_method: "percent_0.23".to_string(),
+        stop_fill: StopFill::GapOpen,
+        fill_model: FillModel::NextOpen,
+        max_volume_participation: 0.0,
+        slippage: SlippageConfig::default(),
+        slippage_file: "".to_string(),
+        trailing_stop: TrailingStop::None,
+        benchmark: Benchmark::BuyAndHold,
         symbols,
         intervals,
         initial_capital,
+        is_portfolio: false,
         items_timestamp_start,
         items_timestamp_end,
         trading_fee: 0.001,
+        fee_schedule: None,
+        fee_file: "".to_string(),
+        data_dir: "".to_string(),
+        cache_dir: "cache".to_string(),
+        results_dir: "results".to_string(),
+        results_db: "backtests".to_string(),
     };
     
     let mut stg = Strategy::new(params);
@@ -4,7 +4,7 @@
 // Author: quantxyz
 // Email: lktsepc@gmail.com
 
-use chrono::{TimeZone, Utc, DateTime};
+use chrono::{TimeZone, Utc, DateTime, Months};
 pub fn find_max_last_n(vec: &[f64], n: usize) -> f64 {
     if vec.is_empty() || n == 0 || n > vec.len() {
         return -1.00;
@@ -80,3 +80,41 @@ pub fn timestamp_millis_to_datetime(timestamp_millis: i64) -> DateTime<Utc> {
         chrono::LocalResult::Ambiguous(_, _) => panic!("Ambiguous timestamp"),
     }
 }
+
+// K 线周期的毫秒数, 1M 按 31 天计算(上限)
+pub fn interval_millis(interval: &str) -> Option<i64> {
+    let (num, unit) = interval.split_at(interval.len().checked_sub(1)?);
+    let num: i64 = num.parse().ok()?;
+    let unit_millis = match unit {
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        "w" => 7 * 24 * 60 * 60 * 1000,
+        "M" => 31 * 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+    Some(num * unit_millis)
+}
+
+// K 线的收盘时间(与币安一致, 为下一根开盘时间减 1 毫秒), 1M 按自然月计算
+pub fn interval_close_time(timestamp: i64, interval: &str) -> i64 {
+    if let Some(months) = interval.strip_suffix('M').and_then(|n| n.parse::<u32>().ok()) {
+        let open = timestamp_millis_to_datetime(timestamp);
+        if let Some(close) = open.checked_add_months(Months::new(months)) {
+            return close.timestamp_millis() - 1;
+        }
+    }
+    timestamp + interval_millis(interval).unwrap_or(0) - 1
+}
+
+// 统一时间戳为毫秒: 秒(10位)/毫秒(13位)/微秒(16位)
+pub fn normalize_timestamp_millis(timestamp: i64) -> i64 {
+    if timestamp.abs() < 100_000_000_000 {
+        timestamp * 1000
+    } else if timestamp.abs() >= 100_000_000_000_000 {
+        timestamp / 1000
+    } else {
+        timestamp
+    }
+}
@@ -4,9 +4,12 @@
 // Author: quantxyz
 // Email: lktsepc@gmail.com
 
-use futures::stream::StreamExt;
 use mongodb::bson::doc;
-use mongodb::{bson::Document, options::FindOptions, Client, Collection};
+use mongodb::options::{FindOptions, ReplaceOptions};
+use mongodb::{bson::Document, Client, Collection, Cursor, Database};
+
+// 批量写入时每个请求的文档数
+const BULK_SIZE: usize = 1000;
 
 #[derive(Debug, Clone)]
 pub struct ClientMongo {
@@ -27,17 +30,43 @@ impl ClientMongo {
     pub fn with_db_name(db_name: String) -> Self {
         Self::new(None, Some(db_name))
     }
-    pub async fn records_query(
+    pub fn db_name(&self) -> &str {
+        &self.db_name
+    }
+    async fn database(&self) -> Result<Database, mongodb::error::Error> {
+        let client = Client::with_uri_str(&self.url).await?;
+        Ok(client.database(&self.db_name))
+    }
+    // 分批插入, 返回插入的文档数
+    pub async fn insert_many(&self, collection_name: &str, documents: Vec<Document>) -> Result<usize, mongodb::error::Error> {
+        let collection: Collection<Document> = self.database().await?.collection(collection_name);
+        let mut inserted = 0;
+        for chunk in documents.chunks(BULK_SIZE) {
+            inserted += collection.insert_many(chunk, None).await?.inserted_ids.len();
+        }
+        Ok(inserted)
+    }
+    // 按 filter 替换一条文档, 不存在则插入
+    pub async fn upsert(&self, collection_name: &str, filter: Document, document: Document) -> Result<(), mongodb::error::Error> {
+        let collection: Collection<Document> = self.database().await?.collection(collection_name);
+        let options = ReplaceOptions::builder().upsert(true).build();
+        collection.replace_one(filter, document, options).await?;
+        Ok(())
+    }
+    pub async fn delete_many(&self, collection_name: &str, filter: Document) -> Result<u64, mongodb::error::Error> {
+        let collection: Collection<Document> = self.database().await?.collection(collection_name);
+        Ok(collection.delete_many(filter, None).await?.deleted_count)
+    }
+    // 返回游标, 按需逐条读取, 避免一次性加载整个集合
+    pub async fn records_stream(
         &self,
         collection_name: &str,
         query: Option<Document>,
         limit: Option<i64>,
         sort_col: Option<&str>,
         small_first: Option<bool>,
-    ) -> Result<Vec<Document>, mongodb::error::Error> {
-        let client = Client::with_uri_str(&self.url).await?;
-        let db = client.database(&self.db_name);
-        let collection: Collection<Document> = db.collection(collection_name);
+    ) -> Result<Cursor<Document>, mongodb::error::Error> {
+        let collection: Collection<Document> = self.database().await?.collection(collection_name);
         let query = query.unwrap_or_default();
         let limit = limit.unwrap_or(0);
         let sort_col = sort_col.unwrap_or("_id");
@@ -50,18 +79,6 @@ impl ClientMongo {
             .limit(limit)
             .build();
 
-        let mut cursor = collection.find(query, find_options).await?;
-
-        let mut records = Vec::new();
-        while let Some(result) = cursor.next().await {
-            match result {
-                Ok(document) => records.push(document),
-                Err(e) => {
-                    log::error!("error:{}", e);
-                }
-            }
-        }
-
-        Ok(records)
+        collection.find(query, find_options).await
     }
 }